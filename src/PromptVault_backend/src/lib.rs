@@ -1,22 +1,67 @@
-use candid::{CandidType, Principal};
+// Crate name is dictated by the dfx canister name
+#![allow(non_snake_case)]
+
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::stable::{BufferedStableReader, BufferedStableWriter};
 use ic_cdk::api::time;
 use ic_cdk::query;
 use ic_cdk::update;
-use ic_cdk_macros::*;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::Write;
+
+// Ledger types (ICRC-1 interface exposed by the ICP ledger)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
 
-// Add ledger types
 #[derive(CandidType, Deserialize)]
-struct AccountBalance {
-    e8s: u64,
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
 }
 
 #[derive(CandidType, Deserialize)]
-struct Account {
-    owner: Principal,
-    subaccount: Option<Vec<u8>>,
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
 }
 
 // Constants
@@ -25,10 +70,53 @@ const MAX_DESCRIPTION_LENGTH: usize = 500;
 const MAX_CONTENT_LENGTH: usize = 10000;
 const MAX_TAGS: usize = 10;
 const MAX_TAG_LENGTH: usize = 30;
+const DEFAULT_TAG_LIST_SIZE: usize = 50;
+const PREVIEW_LENGTH: usize = 200;
+const PAYWALLED_PREVIEW_DIVISOR: usize = 4; // paywalled previews show at most a quarter of the content
+const MAX_CHANGELOG_LENGTH: usize = 500;
+const MAX_VARIABLES: usize = 20;
+const MAX_VARIABLE_NAME_LENGTH: usize = 50;
+const MAX_VARIABLE_DESCRIPTION_LENGTH: usize = 200;
+const MAX_VARIABLE_OPTIONS: usize = 20;
+const MAX_VARIABLE_VALUE_LENGTH: usize = 1000;
+const MAX_MESSAGES: usize = 50;
+const MAX_REPORT_DETAILS_LENGTH: usize = 1000;
+const MAX_MODERATION_NOTE_LENGTH: usize = 500;
+const MAX_REVIEW_LENGTH: usize = 2000;
+const MAX_REVIEW_REPLY_LENGTH: usize = 1000;
+const MAX_COMMENT_LENGTH: usize = 2000;
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+// Search ranking (BM25) and field weights applied to term frequencies
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+const TITLE_WEIGHT: u32 = 3;
+const TAG_WEIGHT: u32 = 2;
+const DESCRIPTION_WEIGHT: u32 = 1;
+
+// Bayesian rating: every prompt starts with this many ratings at the platform mean
+const RATING_PRIOR_WEIGHT: f64 = 5.0;
+const DEFAULT_RATING_PRIOR: f64 = 3.0; // platform mean before anything is rated
+
+// Default ICP ledger canister (same ID on mainnet and on a local replica deployed per README)
+const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+// Bump whenever `StableState` changes in a way `#[serde(default)]` can't absorb
+// v2: tags canonicalized
+// v3: revision history, seeded with each prompt's current content
+// v4: reviews, seeded from existing ratings
+// v5: integer rating aggregates, rebuilt from USER_RATINGS
+const STATE_VERSION: u32 = 5;
+const STABLE_BUFFER_SIZE: usize = 1024 * 1024;
+
+const MAX_PLATFORM_FEE_BPS: u16 = 10_000;
 
 // Types
 pub type PromptId = u64;
 pub type UserId = Principal;
+pub type ReportId = u64;
+pub type CommentId = u64;
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum PromptCategory {
@@ -57,8 +145,240 @@ pub struct Prompt {
     pub updated_at: u64,
     pub likes: u64,
     pub purchases: u64,
+    pub rating: f64,        // rating_sum / total_ratings
+    pub total_ratings: u64, // sum of rating_counts
+    #[serde(default)]
+    pub rating_sum: u64,
+    #[serde(default)]
+    pub rating_counts: [u64; 5], // index 0 holds the 1-star ratings
+    #[serde(default)]
+    pub deleted_at: Option<u64>, // set when delisted by the author but kept for past buyers
+    #[serde(default)]
+    pub revision: u32, // number of the latest entry in the prompt's revision history
+    #[serde(default)]
+    pub variables: Vec<TemplateVariable>,
+    #[serde(default)]
+    pub messages: Vec<PromptMessage>, // structured form of `content`, empty for plain prompts
+    #[serde(default)]
+    pub hidden_at: Option<u64>, // set by moderators; hidden prompts are not listed
+}
+
+// Declared `{{name}}` placeholder of a prompt's content
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TemplateVariable {
+    pub name: String, // letters, digits and underscores, not starting with a digit
+    pub description: Option<String>,
+    pub default_value: Option<String>, // used by render_prompt when no value is given
+    pub required: bool,
+    pub options: Option<Vec<String>>, // allowed values; any text when None
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum MessageRole {
+    System,
+    User,
+    Assistant,
+}
+
+// One step of a multi-message prompt chain
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PromptMessage {
+    pub role: MessageRole,
+    pub text: String,
+}
+
+// Content as returned to readers: the messages of a chain and the single-text form of it
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PromptContent {
+    pub messages: Vec<PromptMessage>, // empty for plain prompts
+    pub flattened: String,
+}
+
+// Snapshot of the versioned fields of a prompt, numbered from 1
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PromptRevision {
+    pub revision: u32,
+    pub title: String,
+    pub description: String,
+    pub content: String,
+    pub created_at: u64,
+    pub note: Option<String>, // changelog entry given by the author
+    #[serde(default)]
+    pub variables: Vec<TemplateVariable>,
+    #[serde(default)]
+    pub messages: Vec<PromptMessage>,
+}
+
+// Revision history entry without the content
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RevisionSummary {
+    pub revision: u32,
+    pub title: String,
+    pub created_at: u64,
+    pub note: Option<String>,
+}
+
+impl From<&PromptRevision> for RevisionSummary {
+    fn from(revision: &PromptRevision) -> Self {
+        RevisionSummary {
+            revision: revision.revision,
+            title: revision.title.clone(),
+            created_at: revision.created_at,
+            note: revision.note.clone(),
+        }
+    }
+}
+
+// Listing view of a prompt; paid content is reduced to a short preview
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PromptSummary {
+    pub id: PromptId,
+    pub title: String,
+    pub description: String,
+    pub preview: String,
+    pub author: UserId,
+    pub category: PromptCategory,
+    pub tags: Vec<String>,
+    pub price: u64,
+    pub is_premium: bool,
+    pub is_public: bool,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub likes: u64,
+    pub purchases: u64,
     pub rating: f64,
     pub total_ratings: u64,
+    pub deleted_at: Option<u64>,
+    pub revision: u32,
+    pub variables: Vec<TemplateVariable>,
+    pub hidden_at: Option<u64>,
+}
+
+// Paywalled prompts are often shorter than PREVIEW_LENGTH, so their preview is also capped
+// at a share of the content
+fn preview(prompt: &Prompt) -> String {
+    let length = if is_paywalled(prompt) {
+        (prompt.content.chars().count() / PAYWALLED_PREVIEW_DIVISOR).min(PREVIEW_LENGTH)
+    } else {
+        PREVIEW_LENGTH
+    };
+    prompt.content.chars().take(length).collect()
+}
+
+// Summary as `caller` may see it: no preview of an unlisted prompt they can't open
+fn summary_for(prompt: &Prompt, caller: UserId) -> PromptSummary {
+    let mut summary = PromptSummary::from(prompt);
+    if !is_listed(prompt) && !can_access_content(prompt, caller) {
+        summary.preview.clear();
+    }
+    summary
+}
+
+impl From<&Prompt> for PromptSummary {
+    fn from(prompt: &Prompt) -> Self {
+        PromptSummary {
+            id: prompt.id,
+            title: prompt.title.clone(),
+            description: prompt.description.clone(),
+            preview: preview(prompt),
+            author: prompt.author,
+            category: prompt.category.clone(),
+            tags: prompt.tags.clone(),
+            price: prompt.price,
+            is_premium: prompt.is_premium,
+            is_public: prompt.is_public,
+            created_at: prompt.created_at,
+            updated_at: prompt.updated_at,
+            likes: prompt.likes,
+            purchases: prompt.purchases,
+            rating: prompt.rating,
+            total_ratings: prompt.total_ratings,
+            deleted_at: prompt.deleted_at,
+            revision: prompt.revision,
+            variables: prompt.variables.clone(),
+            hidden_at: prompt.hidden_at,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SortBy {
+    Newest,
+    RecentlyUpdated,
+    Price,
+    Rating,
+    MostPurchased,
+    MostLiked,
+    Relevance,      // match score in search, popularity in plain listings
+    WeightedRating, // Bayesian average, so a single 5-star rating doesn't top the list
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SortDirection {
+    Ascending,
+    Descending,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SortOptions {
+    pub sort_by: SortBy,
+    pub direction: Option<SortDirection>, // defaults to Descending
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum MatchMode {
+    All, // every term must match
+    Any, // at least one term must match
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PricingFilter {
+    Free,
+    Premium,
+}
+
+// Terms are matched as whole words; a trailing `*` (and always the last term) matches as a prefix.
+// Every filter that is set must hold; ranges are inclusive.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SearchQuery {
+    pub text: String,
+    pub mode: Option<MatchMode>,                 // defaults to All
+    pub categories: Option<Vec<PromptCategory>>, // any of these
+    pub min_price: Option<u64>,
+    pub max_price: Option<u64>,
+    pub min_rating: Option<f64>,
+    pub min_total_ratings: Option<u64>,
+    pub required_tags: Option<Vec<String>>,
+    pub excluded_tags: Option<Vec<String>>,
+    pub author: Option<UserId>,
+    pub created_after: Option<u64>,
+    pub created_before: Option<u64>,
+    pub pricing: Option<PricingFilter>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: u64, // listed prompts carrying the tag
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SearchHit {
+    pub prompt: PromptSummary,
+    pub score: f64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PageRequest {
+    pub cursor: Option<u64>, // next_cursor from the previous page, None for the first
+    pub limit: Option<u32>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<u64>, // None on the last page
+    pub total: u64,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -71,6 +391,8 @@ pub struct CreatePromptRequest {
     pub price: u64,
     pub is_premium: bool,
     pub is_public: bool,
+    pub variables: Option<Vec<TemplateVariable>>,
+    pub messages: Option<Vec<PromptMessage>>, // when non-empty, `content` is derived from them
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -84,12 +406,68 @@ pub struct UpdatePromptRequest {
     pub price: Option<u64>,
     pub is_premium: Option<bool>,
     pub is_public: Option<bool>,
+    pub variables: Option<Vec<TemplateVariable>>,
+    pub messages: Option<Vec<PromptMessage>>, // an empty list turns the prompt back into plain text
+    pub changelog: Option<String>, // note for the revision created by a title, description or content change
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct RatePromptRequest {
     pub prompt_id: PromptId,
-    pub rating: u8, // 1-5 stars
+    pub rating: u8,             // 1-5 stars
+    pub review: Option<String>, // None keeps the current text when re-rating, "" clears it
+}
+
+// Every rating is a review, with or without text; `rating` mirrors USER_RATINGS
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Review {
+    pub prompt_id: PromptId,
+    pub reviewer: UserId,
+    pub rating: u8,
+    pub text: Option<String>,
+    pub verified_purchase: bool, // the reviewer had bought the prompt when last rating it
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub reply: Option<ReviewReply>, // from the prompt's author
+    pub helpful_votes: u32,
+    pub unhelpful_votes: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReviewReply {
+    pub text: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Comment {
+    pub id: CommentId,
+    pub prompt_id: PromptId,
+    pub author: UserId,
+    pub parent_id: Option<CommentId>, // None for top-level comments
+    pub text: String,                 // emptied once deleted
+    pub created_at: u64,
+    pub edited_at: Option<u64>,
+    pub deleted_at: Option<u64>, // deleted comments stay as placeholders while they have replies
+    pub removed_by_moderator: bool,
+    pub reply_count: u32,
+}
+
+// A rating as recorded in USER_RATINGS, with its review
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct UserRating {
+    pub prompt_id: PromptId,
+    pub rating: u8,
+    pub review: Option<Review>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RatingDistribution {
+    pub counts: [u64; 5], // index 0 holds the 1-star ratings
+    pub total: u64,
+    pub average: f64,
+    pub weighted_rating: f64, // the score SortBy::WeightedRating ranks by
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -99,6 +477,89 @@ pub struct Purchase {
     pub seller: UserId,
     pub price: u64,
     pub timestamp: u64,
+    #[serde(default)]
+    pub block_index: Option<u64>, // ledger block of the payment, None for free prompts
+    #[serde(default)]
+    pub seller_amount: u64, // credited to the seller's balance
+    #[serde(default)]
+    pub fee_amount: u64, // kept by the platform
+    #[serde(default)]
+    pub ledger_fee: u64, // paid by the buyer on top of the price
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PaymentMethod {
+    Deposit,  // canister pulls from the buyer's deposit subaccount
+    Approval, // buyer called icrc2_approve for this canister beforehand
+    Balance,  // debit the buyer's internal balance funded by sync_deposit
+}
+
+// Ledger failures surfaced to the buyer; amounts are in e8s
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum PaymentError {
+    BadFee { expected_fee: u64 },
+    BadBurn { min_burn_amount: u64 },
+    InsufficientFunds { balance: u64 },
+    InsufficientAllowance { allowance: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u64 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u64, message: String },
+    CallFailed { message: String },
+}
+
+// Error of every endpoint; amounts are in e8s
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum PromptVaultError {
+    NotFound { resource: String },
+    Unauthorized,                 // caller lacks the role or ownership the call needs
+    Forbidden { reason: String }, // caller is known but the action isn't allowed for them
+    AlreadyExists { resource: String },
+    AlreadyPurchased,
+    Conflict { reason: String }, // the current state doesn't allow the call
+    OperationInProgress,         // retry once the pending ledger call completes
+    Validation { field: String, reason: String },
+    InsufficientFunds { required: u64, available: u64 },
+    Ledger(PaymentError),
+    AccountRestricted { status: UserStatus }, // caller is suspended or banned
+}
+
+impl PromptVaultError {
+    fn not_found(resource: &str) -> Self {
+        PromptVaultError::NotFound {
+            resource: resource.to_string(),
+        }
+    }
+
+    fn forbidden(reason: impl Into<String>) -> Self {
+        PromptVaultError::Forbidden {
+            reason: reason.into(),
+        }
+    }
+
+    fn conflict(reason: impl Into<String>) -> Self {
+        PromptVaultError::Conflict {
+            reason: reason.into(),
+        }
+    }
+
+    fn validation(field: &str, reason: impl Into<String>) -> Self {
+        PromptVaultError::Validation {
+            field: field.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+// Error of purchase_prompt_v1, kept until clients move to purchase_prompt
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum PurchaseError {
+    PromptNotFound,
+    OwnPrompt,
+    AlreadyPurchased,
+    PurchaseInProgress,
+    Payment(PaymentError),
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -112,8 +573,141 @@ pub struct User {
     pub prompts_created: u64,
     pub prompts_purchased: u64,
     pub balance: u64, // in e8s (1 ICP = 100_000_000 e8s)
+    #[serde(default)]
+    pub status: UserStatus,
+    #[serde(default)]
+    pub warnings: u32, // moderation warnings received
+    #[serde(default)]
+    pub follower_count: u64,
+    #[serde(default)]
+    pub following_count: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub enum UserStatus {
+    #[default]
+    Active,
+    Suspended {
+        until: u64,
+    },
+    Banned,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    pub ledger_canister_id: Principal,
+    pub index_canister_id: Option<Principal>,
+    #[serde(default)]
+    pub platform_fee_bps: u16, // share of each sale kept by the platform, 100 = 1%
+    #[serde(default)]
+    pub treasury: Option<Account>, // receives withdrawn platform fees
+    #[serde(default)]
+    pub rating_eligibility: RatingEligibility,
+}
+
+// Who may rate a prompt on top of having a user record and access to its content
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub enum RatingEligibility {
+    #[default]
+    Anyone,
+    Buyers, // free prompts count once "purchased"
+    MinAccountAge {
+        seconds: u64,
+    },
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            ledger_canister_id: Principal::from_text(ICP_LEDGER_CANISTER_ID)
+                .expect("Invalid ledger canister ID"),
+            index_canister_id: None,
+            platform_fee_bps: 0,
+            treasury: None,
+            rating_eligibility: RatingEligibility::Anyone,
+        }
+    }
+}
+
+// Passed to set_config and in InitArgs; unset fields keep their current value
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ConfigUpdate {
+    pub ledger_canister_id: Option<Principal>,
+    pub index_canister_id: Option<Principal>,
+    pub platform_fee_bps: Option<u16>,
+    pub treasury: Option<Account>,
+    pub rating_eligibility: Option<RatingEligibility>,
+}
+
+// Passed to #[init] and #[post_upgrade]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct InitArgs {
+    pub config: Option<ConfigUpdate>,
+    pub admins: Option<Vec<Principal>>, // granted Admin on top of the canister controllers
+}
+
+// Admins can do everything moderators can
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Role {
+    Admin,
+    Moderator,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoleAssignment {
+    pub user_id: UserId,
+    pub role: Role,
+    pub granted_by: UserId,
+    pub granted_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ReportReason {
+    Spam,
+    Plagiarism,
+    Harmful,
+    Other, // details are required
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ModerationAction {
+    Dismiss,
+    HidePrompt,
+    RemovePrompt, // deletes it for good; once it has buyers, admins only
+    WarnAuthor,
+    SuspendAuthor { until: u64 },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Resolution {
+    pub action: ModerationAction,
+    pub moderator: UserId,
+    pub note: Option<String>,
+    pub resolved_at: u64,
 }
 
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Report {
+    pub id: ReportId,
+    pub prompt_id: PromptId,
+    pub author: UserId, // of the prompt, kept in case the prompt is removed
+    pub reporter: UserId,
+    pub reason: ReportReason,
+    pub details: Option<String>,
+    pub created_at: u64,
+    pub resolution: Option<Resolution>, // None while the report is in the queue
+}
+
+// Running platform revenue, in e8s
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FeeTotals {
+    pub sales_volume: u64,
+    pub fees_collected: u64,
+    pub fees_withdrawn: u64,
+    pub ledger_fees_paid: u64, // by buyers on purchase transfers
+}
+
+// Response shape of the *_v1 endpoints
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct ApiResponse<T> {
     pub success: bool,
@@ -121,918 +715,4955 @@ pub struct ApiResponse<T> {
     pub error: Option<String>,
 }
 
+impl<T> From<Result<T, PromptVaultError>> for ApiResponse<T> {
+    fn from(result: Result<T, PromptVaultError>) -> Self {
+        match result {
+            Ok(data) => ApiResponse {
+                success: true,
+                data: Some(data),
+                error: None,
+            },
+            Err(error) => ApiResponse {
+                success: false,
+                data: None,
+                error: Some(error.to_string()),
+            },
+        }
+    }
+}
+
 // State
 thread_local! {
     static PROMPTS: RefCell<HashMap<PromptId, Prompt>> = RefCell::new(HashMap::new());
     static USERS: RefCell<HashMap<UserId, User>> = RefCell::new(HashMap::new());
-    static PURCHASES: RefCell<Vec<Purchase>> = RefCell::new(Vec::new());
+    static PURCHASES: RefCell<Vec<Purchase>> = const { RefCell::new(Vec::new()) };
     static USER_PURCHASES: RefCell<HashMap<UserId, Vec<PromptId>>> = RefCell::new(HashMap::new());
     static USER_LIKES: RefCell<HashMap<UserId, Vec<PromptId>>> = RefCell::new(HashMap::new());
     static USER_RATINGS: RefCell<HashMap<UserId, HashMap<PromptId, u8>>> = RefCell::new(HashMap::new());
-    static NEXT_PROMPT_ID: RefCell<PromptId> = RefCell::new(1);
+    static NEXT_PROMPT_ID: RefCell<PromptId> = const { RefCell::new(1) };
+    static PROMPT_REVISIONS: RefCell<HashMap<PromptId, Vec<PromptRevision>>> = RefCell::new(HashMap::new());
+    static CONFIG: RefCell<Config> = RefCell::new(Config::default());
+    static FEE_TOTALS: RefCell<FeeTotals> = RefCell::new(FeeTotals::default());
+    // In-flight purchases; not persisted since no call survives an upgrade
+    static PENDING_PURCHASES: RefCell<HashSet<(UserId, PromptId)>> = RefCell::new(HashSet::new());
+    // Derived from PROMPTS and rebuilt after upgrades, so not part of StableState
+    static SEARCH_INDEX: RefCell<SearchIndex> = RefCell::new(SearchIndex::default());
+    // Users with a deposit sync or withdrawal awaiting the ledger
+    static PENDING_BALANCE_OPS: RefCell<HashSet<UserId>> = RefCell::new(HashSet::new());
+    static ROLES: RefCell<HashMap<UserId, RoleAssignment>> = RefCell::new(HashMap::new());
+    static REPORTS: RefCell<BTreeMap<ReportId, Report>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_REPORT_ID: RefCell<ReportId> = const { RefCell::new(1) };
+    static COMMENTS: RefCell<BTreeMap<CommentId, Comment>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_COMMENT_ID: RefCell<CommentId> = const { RefCell::new(1) };
+    // follower -> followed users
+    static USER_FOLLOWING: RefCell<HashMap<UserId, BTreeSet<UserId>>> = RefCell::new(HashMap::new());
+    // Reverse of USER_FOLLOWING, rebuilt after upgrades, so not part of StableState
+    static USER_FOLLOWERS: RefCell<HashMap<UserId, BTreeSet<UserId>>> = RefCell::new(HashMap::new());
+    static REVIEWS: RefCell<HashMap<PromptId, HashMap<UserId, Review>>> = RefCell::new(HashMap::new());
+    // (prompt, reviewer) -> voter -> helpful
+    static REVIEW_VOTES: RefCell<HashMap<(PromptId, UserId), HashMap<UserId, bool>>> = RefCell::new(HashMap::new());
 }
 
-// Helper functions
-fn get_caller() -> UserId {
-    ic_cdk::caller()
+// Snapshot of every thread_local above, written to stable memory across upgrades.
+// New fields must carry `#[serde(default)]` so older snapshots still decode.
+#[derive(Serialize, Deserialize)]
+struct StableState {
+    version: u32,
+    #[serde(default)]
+    prompts: HashMap<PromptId, Prompt>,
+    #[serde(default)]
+    users: HashMap<UserId, User>,
+    #[serde(default)]
+    purchases: Vec<Purchase>,
+    #[serde(default)]
+    user_purchases: HashMap<UserId, Vec<PromptId>>,
+    #[serde(default)]
+    user_likes: HashMap<UserId, Vec<PromptId>>,
+    #[serde(default)]
+    user_ratings: HashMap<UserId, HashMap<PromptId, u8>>,
+    #[serde(default = "default_next_prompt_id")]
+    next_prompt_id: PromptId,
+    #[serde(default)]
+    prompt_revisions: HashMap<PromptId, Vec<PromptRevision>>,
+    #[serde(default)]
+    config: Config,
+    #[serde(default)]
+    fee_totals: FeeTotals,
+    #[serde(default)]
+    roles: HashMap<UserId, RoleAssignment>,
+    #[serde(default)]
+    reports: BTreeMap<ReportId, Report>,
+    #[serde(default = "default_next_report_id")]
+    next_report_id: ReportId,
+    #[serde(default)]
+    reviews: HashMap<PromptId, HashMap<UserId, Review>>,
+    #[serde(default)]
+    review_votes: HashMap<(PromptId, UserId), HashMap<UserId, bool>>,
+    #[serde(default)]
+    comments: BTreeMap<CommentId, Comment>,
+    #[serde(default = "default_next_comment_id")]
+    next_comment_id: CommentId,
+    #[serde(default)]
+    user_following: HashMap<UserId, BTreeSet<UserId>>,
 }
 
-fn get_time() -> u64 {
-    time()
+fn default_next_prompt_id() -> PromptId {
+    1
 }
 
-fn is_authorized(prompt_id: PromptId, caller: UserId) -> bool {
-    PROMPTS.with(|p| {
-        let prompts = p.borrow();
-        match prompts.get(&prompt_id) {
-            Some(prompt) => prompt.author == caller,
-            None => false,
-        }
-    })
+fn default_next_report_id() -> ReportId {
+    1
 }
 
-fn has_purchased(user_id: UserId, prompt_id: PromptId) -> bool {
-    USER_PURCHASES.with(|up| {
-        let user_purchases = up.borrow();
-        match user_purchases.get(&user_id) {
-            Some(purchases) => purchases.contains(&prompt_id),
-            None => false,
+fn default_next_comment_id() -> CommentId {
+    1
+}
+
+impl Default for StableState {
+    fn default() -> Self {
+        StableState {
+            version: STATE_VERSION,
+            prompts: HashMap::new(),
+            users: HashMap::new(),
+            purchases: Vec::new(),
+            user_purchases: HashMap::new(),
+            user_likes: HashMap::new(),
+            user_ratings: HashMap::new(),
+            next_prompt_id: default_next_prompt_id(),
+            prompt_revisions: HashMap::new(),
+            config: Config::default(),
+            fee_totals: FeeTotals::default(),
+            roles: HashMap::new(),
+            reports: BTreeMap::new(),
+            next_report_id: default_next_report_id(),
+            reviews: HashMap::new(),
+            review_votes: HashMap::new(),
+            comments: BTreeMap::new(),
+            next_comment_id: default_next_comment_id(),
+            user_following: HashMap::new(),
         }
-    })
+    }
 }
 
-fn validate_prompt_input(request: &CreatePromptRequest) -> Result<(), String> {
-    if request.title.trim().is_empty() {
-        return Err("Title cannot be empty".to_string());
+// Decodes the snapshot written by pre_upgrade. Builds without a pre_upgrade hook left
+// stable memory empty, so there is nothing to restore and the canister starts afresh.
+fn load_state(stable_pages: u64, reader: impl std::io::Read) -> Result<StableState, String> {
+    if stable_pages == 0 {
+        return Ok(StableState::default());
     }
-    if request.title.len() > MAX_TITLE_LENGTH {
-        return Err(format!(
-            "Title cannot exceed {} characters",
-            MAX_TITLE_LENGTH
-        ));
+    ciborium::from_reader(reader).map_err(|e| format!("Failed to load stable state: {}", e))
+}
+
+fn take_state() -> StableState {
+    StableState {
+        version: STATE_VERSION,
+        prompts: PROMPTS.with(|p| p.take()),
+        users: USERS.with(|u| u.take()),
+        purchases: PURCHASES.with(|p| p.take()),
+        user_purchases: USER_PURCHASES.with(|up| up.take()),
+        user_likes: USER_LIKES.with(|ul| ul.take()),
+        user_ratings: USER_RATINGS.with(|ur| ur.take()),
+        next_prompt_id: NEXT_PROMPT_ID.with(|id| *id.borrow()),
+        prompt_revisions: PROMPT_REVISIONS.with(|pr| pr.take()),
+        config: CONFIG.with(|c| c.take()),
+        fee_totals: FEE_TOTALS.with(|f| f.take()),
+        roles: ROLES.with(|r| r.take()),
+        reports: REPORTS.with(|r| r.take()),
+        next_report_id: NEXT_REPORT_ID.with(|id| *id.borrow()),
+        reviews: REVIEWS.with(|r| r.take()),
+        review_votes: REVIEW_VOTES.with(|rv| rv.take()),
+        comments: COMMENTS.with(|c| c.take()),
+        next_comment_id: NEXT_COMMENT_ID.with(|id| *id.borrow()),
+        user_following: USER_FOLLOWING.with(|uf| uf.take()),
     }
-    if request.description.len() > MAX_DESCRIPTION_LENGTH {
+}
+
+fn restore_state(state: StableState) -> Result<(), String> {
+    if state.version > STATE_VERSION {
         return Err(format!(
-            "Description cannot exceed {} characters",
-            MAX_DESCRIPTION_LENGTH
+            "Stable state version {} is newer than supported version {}",
+            state.version, STATE_VERSION
         ));
     }
-    if request.content.trim().is_empty() {
-        return Err("Content cannot be empty".to_string());
+
+    let state = migrate_state(state);
+
+    PROMPTS.with(|p| *p.borrow_mut() = state.prompts);
+    USERS.with(|u| *u.borrow_mut() = state.users);
+    PURCHASES.with(|p| *p.borrow_mut() = state.purchases);
+    USER_PURCHASES.with(|up| *up.borrow_mut() = state.user_purchases);
+    USER_LIKES.with(|ul| *ul.borrow_mut() = state.user_likes);
+    USER_RATINGS.with(|ur| *ur.borrow_mut() = state.user_ratings);
+    NEXT_PROMPT_ID.with(|id| *id.borrow_mut() = state.next_prompt_id);
+    PROMPT_REVISIONS.with(|pr| *pr.borrow_mut() = state.prompt_revisions);
+    CONFIG.with(|c| *c.borrow_mut() = state.config);
+    FEE_TOTALS.with(|f| *f.borrow_mut() = state.fee_totals);
+    ROLES.with(|r| *r.borrow_mut() = state.roles);
+    REPORTS.with(|r| *r.borrow_mut() = state.reports);
+    NEXT_REPORT_ID.with(|id| *id.borrow_mut() = state.next_report_id);
+    REVIEWS.with(|r| *r.borrow_mut() = state.reviews);
+    REVIEW_VOTES.with(|rv| *rv.borrow_mut() = state.review_votes);
+    COMMENTS.with(|c| *c.borrow_mut() = state.comments);
+    NEXT_COMMENT_ID.with(|id| *id.borrow_mut() = state.next_comment_id);
+    USER_FOLLOWING.with(|uf| *uf.borrow_mut() = state.user_following);
+    rebuild_search_index();
+    rebuild_followers();
+    Ok(())
+}
+
+// Brings snapshots written by older versions up to the current schema
+fn migrate_state(mut state: StableState) -> StableState {
+    if state.version < 2 {
+        for prompt in state.prompts.values_mut() {
+            let mut tags: Vec<String> = Vec::new();
+            for tag in &prompt.tags {
+                if let Ok(tag) = canonicalize_tag(tag) {
+                    if !tags.contains(&tag) {
+                        tags.push(tag);
+                    }
+                }
+            }
+            prompt.tags = tags;
+        }
     }
-    if request.content.len() > MAX_CONTENT_LENGTH {
-        return Err(format!(
-            "Content cannot exceed {} characters",
-            MAX_CONTENT_LENGTH
-        ));
+    if state.version < 3 {
+        for prompt in state.prompts.values_mut() {
+            prompt.revision = 1;
+            state.prompt_revisions.insert(
+                prompt.id,
+                vec![PromptRevision {
+                    revision: 1,
+                    title: prompt.title.clone(),
+                    description: prompt.description.clone(),
+                    content: prompt.content.clone(),
+                    created_at: prompt.updated_at,
+                    note: None,
+                    variables: prompt.variables.clone(),
+                    messages: prompt.messages.clone(),
+                }],
+            );
+        }
     }
-    if request.tags.len() > MAX_TAGS {
-        return Err(format!("Cannot have more than {} tags", MAX_TAGS));
+    if state.version < 4 {
+        for (reviewer, ratings) in &state.user_ratings {
+            for (prompt_id, rating) in ratings {
+                // Rating times weren't kept; the prompt's creation is the best lower bound
+                let Some(prompt) = state.prompts.get(prompt_id) else {
+                    continue;
+                };
+                let verified_purchase = state
+                    .user_purchases
+                    .get(reviewer)
+                    .is_some_and(|purchases| purchases.contains(prompt_id));
+                state.reviews.entry(*prompt_id).or_default().insert(
+                    *reviewer,
+                    Review {
+                        prompt_id: *prompt_id,
+                        reviewer: *reviewer,
+                        rating: *rating,
+                        text: None,
+                        verified_purchase,
+                        created_at: prompt.created_at,
+                        updated_at: prompt.created_at,
+                        reply: None,
+                        helpful_votes: 0,
+                        unhelpful_votes: 0,
+                    },
+                );
+            }
+        }
     }
-    for tag in &request.tags {
-        if tag.len() > MAX_TAG_LENGTH {
-            return Err(format!("Tag cannot exceed {} characters", MAX_TAG_LENGTH));
+    if state.version < 5 {
+        // The f64 running average may have drifted, so start over from the ratings themselves
+        for prompt in state.prompts.values_mut() {
+            prompt.rating_sum = 0;
+            prompt.rating_counts = [0; 5];
+            update_rating_aggregates(prompt, None, None);
+        }
+        for ratings in state.user_ratings.values() {
+            for (prompt_id, rating) in ratings {
+                if let Some(prompt) = state.prompts.get_mut(prompt_id) {
+                    update_rating_aggregates(prompt, None, Some(*rating));
+                }
+            }
         }
     }
-    Ok(())
+    state.version = STATE_VERSION;
+    state
 }
 
-fn validate_rating(rating: u8) -> Result<(), String> {
-    if rating < 1 || rating > 5 {
-        return Err("Rating must be between 1 and 5".to_string());
-    }
-    Ok(())
+// Inverted index over title, description and tags, plus the tag directory
+#[derive(Default)]
+struct SearchIndex {
+    // term -> prompt -> weighted term frequency
+    postings: BTreeMap<String, HashMap<PromptId, u32>>,
+    // prompt -> weighted term frequencies, kept to unindex
+    documents: HashMap<PromptId, HashMap<String, u32>>,
+    // prompt -> sum of its weighted term frequencies
+    lengths: HashMap<PromptId, u32>,
+    total_length: u64,
+    // canonical tag -> prompts carrying it
+    tags: BTreeMap<String, BTreeSet<PromptId>>,
+    document_tags: HashMap<PromptId, Vec<String>>,
 }
 
-fn safe_f64_average(total: f64, count: u64) -> f64 {
-    if count == 0 {
-        0.0
-    } else {
-        let avg = total / count as f64;
-        if avg.is_finite() {
-            avg
-        } else {
-            0.0
-        }
-    }
+struct QueryTerm {
+    text: String,
+    prefix: bool,
 }
 
-// Public functions
-#[init]
-fn init() {
-    ic_cdk::println!("Prompt Vault initialized");
-}
+impl SearchIndex {
+    fn insert(&mut self, prompt: &Prompt) {
+        self.remove(prompt.id);
 
-#[update]
-fn create_user(username: Option<String>, email: Option<String>) -> ApiResponse<User> {
-    let caller = get_caller();
+        let mut terms: HashMap<String, u32> = HashMap::new();
+        let fields = [
+            (prompt.title.as_str(), TITLE_WEIGHT),
+            (prompt.description.as_str(), DESCRIPTION_WEIGHT),
+        ];
+        let tags = prompt.tags.iter().map(|tag| (tag.as_str(), TAG_WEIGHT));
+        for (text, weight) in fields.into_iter().chain(tags) {
+            for token in tokenize(text) {
+                *terms.entry(token).or_insert(0) += weight;
+            }
+        }
 
-    // Check if user already exists
-    let user_exists = USERS.with(|u| {
-        let users = u.borrow();
-        users.contains_key(&caller)
-    });
+        for (term, frequency) in &terms {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .insert(prompt.id, *frequency);
+        }
+        let length: u32 = terms.values().sum();
+        self.total_length += length as u64;
+        self.lengths.insert(prompt.id, length);
+        self.documents.insert(prompt.id, terms);
 
-    if user_exists {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("User already exists".to_string()),
-        };
+        for tag in &prompt.tags {
+            self.tags.entry(tag.clone()).or_default().insert(prompt.id);
+        }
+        self.document_tags.insert(prompt.id, prompt.tags.clone());
     }
 
-    // Validate username if provided
-    if let Some(ref name) = username {
-        if name.trim().is_empty() || name.len() > 50 {
-            return ApiResponse {
-                success: false,
-                data: None,
-                error: Some("Username must be between 1 and 50 characters".to_string()),
-            };
+    fn remove(&mut self, prompt_id: PromptId) {
+        for tag in self.document_tags.remove(&prompt_id).unwrap_or_default() {
+            if let Some(prompt_ids) = self.tags.get_mut(&tag) {
+                prompt_ids.remove(&prompt_id);
+                if prompt_ids.is_empty() {
+                    self.tags.remove(&tag);
+                }
+            }
         }
-    }
 
-    let user = User {
-        id: caller,
-        username,
-        email,
-        joined_at: get_time(),
-        total_earnings: 0,
-        total_spent: 0,
-        prompts_created: 0,
-        prompts_purchased: 0,
-        balance: 0,
-    };
+        let terms = match self.documents.remove(&prompt_id) {
+            Some(terms) => terms,
+            None => return,
+        };
+        self.total_length -= self.lengths.remove(&prompt_id).unwrap_or(0) as u64;
 
-    USERS.with(|u| {
-        let mut users = u.borrow_mut();
-        users.insert(caller, user.clone());
-    });
+        for term in terms.into_keys() {
+            if let Some(postings) = self.postings.get_mut(&term) {
+                postings.remove(&prompt_id);
+                if postings.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
 
-    ApiResponse {
-        success: true,
-        data: Some(user),
-        error: None,
+    fn document_length(&self, prompt_id: PromptId) -> u32 {
+        self.lengths.get(&prompt_id).copied().unwrap_or(0)
     }
-}
 
-#[ic_cdk::query]
-fn get_user(user_id: UserId) -> ApiResponse<User> {
-    USERS.with(|u| {
-        let users = u.borrow();
-        match users.get(&user_id) {
-            Some(user) => ApiResponse {
-                success: true,
-                data: Some(user.clone()),
-                error: None,
-            },
-            None => ApiResponse {
-                success: false,
-                data: None,
-                error: Some("User not found".to_string()),
-            },
+    // BM25 score of every prompt matching the query terms under the given mode
+    fn search(&self, terms: &[QueryTerm], mode: &MatchMode) -> HashMap<PromptId, f64> {
+        let document_count = self.documents.len() as f64;
+        if document_count == 0.0 {
+            return HashMap::new();
         }
-    })
-}
+        let average_length = self.total_length as f64 / document_count;
 
-#[ic_cdk::update]
-fn create_prompt(request: CreatePromptRequest) -> ApiResponse<Prompt> {
-    let caller = get_caller();
+        let mut scores: HashMap<PromptId, f64> = HashMap::new();
+        let mut matched_terms: HashMap<PromptId, usize> = HashMap::new();
+        for term in terms {
+            let mut term_scores: HashMap<PromptId, f64> = HashMap::new();
+            for postings in self.expand(term) {
+                let matching = postings.len() as f64;
+                let idf = ((document_count - matching + 0.5) / (matching + 0.5) + 1.0).ln();
+                for (prompt_id, frequency) in postings {
+                    let frequency = *frequency as f64;
+                    let length = self.document_length(*prompt_id) as f64;
+                    let norm = 1.0 - BM25_B + BM25_B * length / average_length;
+                    *term_scores.entry(*prompt_id).or_insert(0.0) +=
+                        idf * frequency * (BM25_K1 + 1.0) / (frequency + BM25_K1 * norm);
+                }
+            }
+            for (prompt_id, score) in term_scores {
+                *scores.entry(prompt_id).or_insert(0.0) += score;
+                *matched_terms.entry(prompt_id).or_insert(0) += 1;
+            }
+        }
 
-    // Validate input
-    if let Err(error) = validate_prompt_input(&request) {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some(error),
-        };
+        if *mode == MatchMode::All {
+            scores.retain(|prompt_id, _| matched_terms.get(prompt_id) == Some(&terms.len()));
+        }
+        scores
     }
 
-    // Ensure user exists
-    let user_exists = USERS.with(|u| {
-        let users = u.borrow();
-        users.contains_key(&caller)
-    });
-
-    if !user_exists {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("User not found. Please create a user first.".to_string()),
-        };
+    fn expand<'a>(&'a self, term: &'a QueryTerm) -> Vec<&'a HashMap<PromptId, u32>> {
+        if term.prefix {
+            self.postings
+                .range(term.text.clone()..)
+                .take_while(|(indexed, _)| indexed.starts_with(&term.text))
+                .map(|(_, postings)| postings)
+                .collect()
+        } else {
+            self.postings.get(&term.text).into_iter().collect()
+        }
     }
+}
 
-    let prompt_id = NEXT_PROMPT_ID.with(|id| {
-        let mut next_id = id.borrow_mut();
-        let current_id = *next_id;
-        *next_id += 1;
-        current_id
-    });
-
-    let now = get_time();
-    let prompt = Prompt {
-        id: prompt_id,
-        title: request.title.trim().to_string(),
-        description: request.description,
-        content: request.content,
-        author: caller,
-        category: request.category,
-        tags: request.tags,
-        price: request.price,
-        is_premium: request.is_premium,
-        is_public: request.is_public,
-        created_at: now,
-        updated_at: now,
-        likes: 0,
-        purchases: 0,
-        rating: 0.0,
-        total_ratings: 0,
-    };
+// Lowercased, trimmed, inner whitespace and underscores turned into dashes
+fn canonicalize_tag(tag: &str) -> Result<String, String> {
+    let canonical = tag
+        .trim()
+        .to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '_')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
 
-    PROMPTS.with(|p| {
-        let mut prompts = p.borrow_mut();
-        prompts.insert(prompt_id, prompt.clone());
-    });
+    if canonical.is_empty() {
+        return Err("Tags cannot be empty".to_string());
+    }
+    if canonical.len() > MAX_TAG_LENGTH {
+        return Err(format!("Tag cannot exceed {} characters", MAX_TAG_LENGTH));
+    }
+    if let Some(c) = canonical
+        .chars()
+        .find(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || "-+#.".contains(*c)))
+    {
+        return Err(format!(
+            "Tag \"{}\" contains unsupported character '{}'",
+            tag, c
+        ));
+    }
+    Ok(canonical)
+}
 
-    // Update user stats
-    USERS.with(|u| {
-        let mut users = u.borrow_mut();
-        if let Some(user) = users.get_mut(&caller) {
-            user.prompts_created += 1;
+// Canonicalizes every tag and drops duplicates, keeping the first occurrence
+fn normalize_tags(tags: &[String]) -> Result<Vec<String>, String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = canonicalize_tag(tag)?;
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
         }
-    });
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(format!("Cannot have more than {} tags", MAX_TAGS));
+    }
+    Ok(normalized)
+}
 
-    ApiResponse {
-        success: true,
-        data: Some(prompt),
-        error: None,
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+        .collect()
+}
+
+fn parse_query(text: &str, prefix_all: bool) -> Vec<QueryTerm> {
+    let mut terms: Vec<QueryTerm> = Vec::new();
+    for word in text.split_whitespace() {
+        let prefix = prefix_all || word.ends_with('*');
+        for token in tokenize(word) {
+            terms.push(QueryTerm {
+                text: token,
+                prefix,
+            });
+        }
+    }
+    if let Some(last) = terms.last_mut() {
+        last.prefix = true;
     }
+    terms
 }
 
-#[ic_cdk::query]
-fn get_prompt(prompt_id: PromptId) -> ApiResponse<Prompt> {
+fn index_prompt(prompt_id: PromptId) {
     PROMPTS.with(|p| {
         let prompts = p.borrow();
-        match prompts.get(&prompt_id) {
-            Some(prompt) => ApiResponse {
-                success: true,
-                data: Some(prompt.clone()),
-                error: None,
-            },
-            None => ApiResponse {
-                success: false,
-                data: None,
-                error: Some("Prompt not found".to_string()),
-            },
-        }
+        SEARCH_INDEX.with(|si| {
+            let mut index = si.borrow_mut();
+            match prompts.get(&prompt_id) {
+                Some(prompt) => index.insert(prompt),
+                None => index.remove(prompt_id),
+            }
+        })
     })
 }
 
-#[ic_cdk::query]
-fn get_public_prompts() -> ApiResponse<Vec<Prompt>> {
+fn rebuild_search_index() {
     PROMPTS.with(|p| {
         let prompts = p.borrow();
-        let public_prompts: Vec<Prompt> = prompts
-            .values()
-            .filter(|prompt| prompt.is_public)
-            .cloned()
-            .collect();
+        SEARCH_INDEX.with(|si| {
+            let mut index = SearchIndex::default();
+            for prompt in prompts.values() {
+                index.insert(prompt);
+            }
+            *si.borrow_mut() = index;
+        })
+    })
+}
 
-        ApiResponse {
-            success: true,
-            data: Some(public_prompts),
-            error: None,
+fn rebuild_followers() {
+    USER_FOLLOWING.with(|uf| {
+        let mut followers: HashMap<UserId, BTreeSet<UserId>> = HashMap::new();
+        for (follower, followed) in uf.borrow().iter() {
+            for user_id in followed {
+                followers.entry(*user_id).or_default().insert(*follower);
+            }
         }
+        USER_FOLLOWERS.with(|uf| *uf.borrow_mut() = followers);
     })
 }
 
-#[ic_cdk::query]
-fn get_user_prompts(user_id: UserId) -> ApiResponse<Vec<Prompt>> {
+// Scores for a text query; None when the query has no terms and so matches everything
+fn text_match_scores(
+    text: &str,
+    mode: &MatchMode,
+    prefix_all: bool,
+) -> Option<HashMap<PromptId, f64>> {
+    let terms = parse_query(text, prefix_all);
+    if terms.is_empty() {
+        return None;
+    }
+    Some(SEARCH_INDEX.with(|si| si.borrow().search(&terms, mode)))
+}
+
+// Helper functions
+fn get_caller() -> UserId {
+    ic_cdk::caller()
+}
+
+fn get_time() -> u64 {
+    time()
+}
+
+fn is_authorized(prompt_id: PromptId, caller: UserId) -> bool {
     PROMPTS.with(|p| {
         let prompts = p.borrow();
-        let user_prompts: Vec<Prompt> = prompts
-            .values()
-            .filter(|prompt| prompt.author == user_id)
-            .cloned()
-            .collect();
-
-        ApiResponse {
-            success: true,
-            data: Some(user_prompts),
-            error: None,
+        match prompts.get(&prompt_id) {
+            Some(prompt) => prompt.author == caller,
+            None => false,
         }
     })
 }
 
-#[ic_cdk::update]
-fn update_prompt(request: UpdatePromptRequest) -> ApiResponse<Prompt> {
-    let caller = get_caller();
+// Shown in listings and search: public, not deleted by its author, not hidden by
+// moderators and not written by a banned author
+fn is_listed(prompt: &Prompt) -> bool {
+    prompt.is_public
+        && prompt.deleted_at.is_none()
+        && prompt.hidden_at.is_none()
+        && !is_banned(prompt.author)
+}
 
-    if !is_authorized(request.id, caller) {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Unauthorized".to_string()),
-        };
+fn is_banned(user_id: UserId) -> bool {
+    USERS.with(|u| {
+        u.borrow()
+            .get(&user_id)
+            .is_some_and(|user| user.status == UserStatus::Banned)
+    })
+}
+
+// Guard for marketplace actions; a suspension lapses on its own once `until` passes.
+// Callers without a user record are left to the endpoint's own checks
+fn require_active(user_id: UserId) -> Result<(), PromptVaultError> {
+    check_active(user_id, get_time())
+}
+
+fn check_active(user_id: UserId, now: u64) -> Result<(), PromptVaultError> {
+    let status = USERS.with(|u| {
+        u.borrow()
+            .get(&user_id)
+            .map(|user| user.status.clone())
+            .unwrap_or_default()
+    });
+    match status {
+        UserStatus::Active => Ok(()),
+        UserStatus::Suspended { until } if until <= now => Ok(()),
+        status => Err(PromptVaultError::AccountRestricted { status }),
     }
+}
 
-    PROMPTS.with(|p| {
-        let mut prompts = p.borrow_mut();
-        match prompts.get_mut(&request.id) {
-            Some(prompt) => {
-                if let Some(title) = request.title {
-                    if title.trim().is_empty() || title.len() > MAX_TITLE_LENGTH {
-                        return ApiResponse {
-                            success: false,
-                            data: None,
-                            error: Some("Invalid title".to_string()),
-                        };
-                    }
-                    prompt.title = title.trim().to_string();
-                }
-                if let Some(description) = request.description {
-                    if description.len() > MAX_DESCRIPTION_LENGTH {
-                        return ApiResponse {
-                            success: false,
-                            data: None,
-                            error: Some("Description too long".to_string()),
-                        };
-                    }
-                    prompt.description = description;
-                }
-                if let Some(content) = request.content {
-                    if content.trim().is_empty() || content.len() > MAX_CONTENT_LENGTH {
-                        return ApiResponse {
-                            success: false,
-                            data: None,
-                            error: Some("Invalid content".to_string()),
-                        };
-                    }
-                    prompt.content = content;
-                }
-                if let Some(category) = request.category {
-                    prompt.category = category;
-                }
-                if let Some(tags) = request.tags {
-                    if tags.len() > MAX_TAGS {
-                        return ApiResponse {
-                            success: false,
-                            data: None,
-                            error: Some("Too many tags".to_string()),
-                        };
-                    }
-                    prompt.tags = tags;
-                }
-                if let Some(price) = request.price {
-                    prompt.price = price;
-                }
-                if let Some(is_premium) = request.is_premium {
-                    prompt.is_premium = is_premium;
-                }
-                if let Some(is_public) = request.is_public {
-                    prompt.is_public = is_public;
-                }
-                prompt.updated_at = get_time();
+// Premium or priced prompts reveal their content only to the author and buyers
+fn is_paywalled(prompt: &Prompt) -> bool {
+    prompt.is_premium || prompt.price > 0
+}
 
-                ApiResponse {
-                    success: true,
-                    data: Some(prompt.clone()),
-                    error: None,
-                }
-            }
-            None => ApiResponse {
-                success: false,
-                data: None,
-                error: Some("Prompt not found".to_string()),
-            },
-        }
-    })
+// Authors see all their prompts but deleted ones; everyone else sees the listed ones
+fn is_shown_on_profile(prompt: &Prompt, user_id: UserId, caller: UserId) -> bool {
+    prompt.author == user_id
+        && prompt.deleted_at.is_none()
+        && (caller == user_id || is_listed(prompt))
 }
 
-#[ic_cdk::update]
-fn delete_prompt(prompt_id: PromptId) -> ApiResponse<String> {
-    let caller = get_caller();
+fn can_access_content(prompt: &Prompt, caller: UserId) -> bool {
+    prompt.author == caller
+        || has_purchased(caller, prompt.id)
+        || (is_listed(prompt) && !is_paywalled(prompt))
+}
 
-    if !is_authorized(prompt_id, caller) {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Unauthorized".to_string()),
-        };
+// Cuts one page out of already ordered results; the cursor is the offset of the
+// page's first item, so cursors past the end give an empty page
+fn paginate<T, U>(items: Vec<T>, page: &PageRequest, map: impl Fn(T) -> U) -> Page<U> {
+    let total = items.len();
+    let limit = page
+        .limit
+        .map_or(DEFAULT_PAGE_SIZE, |limit| limit as usize)
+        .clamp(1, MAX_PAGE_SIZE);
+    // Saturate rather than truncate where usize is 32 bits, as on wasm32
+    let start = page
+        .cursor
+        .map_or(0, |cursor| usize::try_from(cursor).unwrap_or(usize::MAX))
+        .min(total);
+    let end = start.saturating_add(limit).min(total);
+
+    Page {
+        items: items
+            .into_iter()
+            .skip(start)
+            .take(end - start)
+            .map(map)
+            .collect(),
+        next_cursor: if end < total { Some(end as u64) } else { None },
+        total: total as u64,
     }
+}
 
-    let removed = PROMPTS.with(|p| {
-        let mut prompts = p.borrow_mut();
-        prompts.remove(&prompt_id).is_some()
-    });
+fn has_tag(prompt: &Prompt, tag: &str) -> bool {
+    canonicalize_tag(tag).is_ok_and(|tag| prompt.tags.contains(&tag))
+}
 
-    if removed {
-        // Update user stats
-        USERS.with(|u| {
-            let mut users = u.borrow_mut();
-            if let Some(user) = users.get_mut(&caller) {
-                if user.prompts_created > 0 {
-                    user.prompts_created -= 1;
-                }
-            }
-        });
+// Structured filters shared by the search endpoints; text matching goes through the index
+fn matches_search_filters(prompt: &Prompt, query: &SearchQuery) -> bool {
+    // Only search listed prompts
+    if !is_listed(prompt) {
+        return false;
+    }
 
-        ApiResponse {
-            success: true,
-            data: Some("Prompt deleted successfully".to_string()),
-            error: None,
+    if let Some(ref categories) = query.categories {
+        if !categories.is_empty() && !categories.contains(&prompt.category) {
+            return false;
         }
-    } else {
-        ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Prompt not found".to_string()),
+    }
+    if query.min_price.is_some_and(|min| prompt.price < min)
+        || query.max_price.is_some_and(|max| prompt.price > max)
+    {
+        return false;
+    }
+    if query.min_rating.is_some_and(|min| prompt.rating < min)
+        || query
+            .min_total_ratings
+            .is_some_and(|min| prompt.total_ratings < min)
+    {
+        return false;
+    }
+    if let Some(ref tags) = query.required_tags {
+        if !tags.iter().all(|tag| has_tag(prompt, tag)) {
+            return false;
         }
     }
+    if let Some(ref tags) = query.excluded_tags {
+        if tags.iter().any(|tag| has_tag(prompt, tag)) {
+            return false;
+        }
+    }
+    if query.author.is_some_and(|author| prompt.author != author) {
+        return false;
+    }
+    if query
+        .created_after
+        .is_some_and(|after| prompt.created_at < after)
+        || query
+            .created_before
+            .is_some_and(|before| prompt.created_at > before)
+    {
+        return false;
+    }
+    match query.pricing {
+        Some(PricingFilter::Free) => !is_paywalled(prompt),
+        Some(PricingFilter::Premium) => is_paywalled(prompt),
+        None => true,
+    }
 }
 
-#[ic_cdk::update]
-fn purchase_prompt(prompt_id: PromptId) -> ApiResponse<String> {
-    let caller = get_caller();
+// Relevance score used to order search results
+fn popularity_score(prompt: &Prompt) -> u64 {
+    prompt.likes + prompt.purchases + (prompt.rating * 10.0) as u64
+}
 
-    // Check if prompt exists
-    let prompt_info = PROMPTS.with(|p| {
-        let prompts = p.borrow();
-        prompts.get(&prompt_id).cloned()
+// Moves one rating in or out of the integer aggregates, then derives `rating` and
+// `total_ratings` from them; ratings outside 1-5 are ignored
+fn update_rating_aggregates(prompt: &mut Prompt, previous: Option<u8>, rating: Option<u8>) {
+    if let Some(previous) = previous.filter(|r| (1..=5).contains(r)) {
+        let count = &mut prompt.rating_counts[previous as usize - 1];
+        *count = count.saturating_sub(1);
+        prompt.rating_sum = prompt.rating_sum.saturating_sub(previous as u64);
+    }
+    if let Some(rating) = rating.filter(|r| (1..=5).contains(r)) {
+        prompt.rating_counts[rating as usize - 1] += 1;
+        prompt.rating_sum += rating as u64;
+    }
+    prompt.total_ratings = prompt.rating_counts.iter().sum();
+    prompt.rating = safe_f64_average(prompt.rating_sum as f64, prompt.total_ratings);
+}
+
+// Mean of every rating on the platform, the prior of weighted_rating
+fn rating_prior() -> f64 {
+    let (sum, count) = PROMPTS.with(|p| {
+        p.borrow()
+            .values()
+            .fold((0u64, 0u64), |(sum, count), prompt| {
+                (sum + prompt.rating_sum, count + prompt.total_ratings)
+            })
     });
+    if count == 0 {
+        DEFAULT_RATING_PRIOR
+    } else {
+        sum as f64 / count as f64
+    }
+}
+
+fn weighted_rating(prompt: &Prompt, prior: f64) -> f64 {
+    (RATING_PRIOR_WEIGHT * prior + prompt.rating_sum as f64)
+        / (RATING_PRIOR_WEIGHT + prompt.total_ratings as f64)
+}
 
-    let prompt = match prompt_info {
-        Some(p) => p,
+// Orders by the requested key with PromptId as tie-breaker; without options, by PromptId
+fn sort_prompts(prompts: &mut [&Prompt], sort: Option<&SortOptions>) {
+    let sort = match sort {
+        Some(sort) => sort,
         None => {
-            return ApiResponse {
-                success: false,
-                data: None,
-                error: Some("Prompt not found".to_string()),
-            }
+            prompts.sort_by_key(|prompt| prompt.id);
+            return;
         }
     };
 
-    // Check if user is trying to buy their own prompt
-    if prompt.author == caller {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Cannot purchase your own prompt".to_string()),
+    let prior = match sort.sort_by {
+        SortBy::WeightedRating => rating_prior(),
+        _ => DEFAULT_RATING_PRIOR,
+    };
+    prompts.sort_by(|a, b| {
+        let ordering = match sort.sort_by {
+            SortBy::Newest => a.created_at.cmp(&b.created_at),
+            SortBy::RecentlyUpdated => a.updated_at.cmp(&b.updated_at),
+            SortBy::Price => a.price.cmp(&b.price),
+            SortBy::Rating => a.rating.total_cmp(&b.rating),
+            SortBy::MostPurchased => a.purchases.cmp(&b.purchases),
+            SortBy::MostLiked => a.likes.cmp(&b.likes),
+            SortBy::Relevance => popularity_score(a).cmp(&popularity_score(b)),
+            SortBy::WeightedRating => {
+                weighted_rating(a, prior).total_cmp(&weighted_rating(b, prior))
+            }
         };
-    }
-
-    // Check if already purchased
-    if has_purchased(caller, prompt_id) {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Prompt already purchased".to_string()),
+        let ordering = match sort.direction {
+            Some(SortDirection::Ascending) => ordering,
+            Some(SortDirection::Descending) | None => ordering.reverse(),
         };
-    }
+        ordering.then(a.id.cmp(&b.id))
+    });
+}
 
-    // For now, we'll simulate the payment process
-    // In a real implementation, you'd integrate with ICP ledger
+fn is_deleted(prompt_id: PromptId) -> bool {
+    PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        prompts
+            .get(&prompt_id)
+            .is_some_and(|prompt| prompt.deleted_at.is_some())
+    })
+}
 
-    let purchase = Purchase {
-        prompt_id,
-        buyer: caller,
-        seller: prompt.author,
-        price: prompt.price,
-        timestamp: get_time(),
-    };
+// Removes the prompt for good; buyers lose access, so only safe without buyers or for admins
+// Refused while a purchase of the prompt awaits the ledger, so the buyer isn't left
+// paying for a prompt that no longer exists
+fn remove_prompt(prompt_id: PromptId) -> Result<(), PromptVaultError> {
+    if has_pending_purchase(prompt_id) {
+        return Err(PromptVaultError::OperationInProgress);
+    }
 
-    // Record purchase
-    PURCHASES.with(|p| {
-        let mut purchases = p.borrow_mut();
-        purchases.push(purchase);
+    let prompt = PROMPTS
+        .with(|p| {
+            let mut prompts = p.borrow_mut();
+            prompts.remove(&prompt_id)
+        })
+        .ok_or_else(|| PromptVaultError::not_found("Prompt"))?;
+    PROMPT_REVISIONS.with(|pr| {
+        pr.borrow_mut().remove(&prompt_id);
     });
-
-    // Update user purchases
-    USER_PURCHASES.with(|up| {
-        let mut user_purchases = up.borrow_mut();
-        user_purchases
-            .entry(caller)
-            .or_insert_with(Vec::new)
-            .push(prompt_id);
+    REVIEWS.with(|r| {
+        r.borrow_mut().remove(&prompt_id);
     });
-
-    // Update prompt stats
-    PROMPTS.with(|p| {
-        let mut prompts = p.borrow_mut();
-        if let Some(prompt) = prompts.get_mut(&prompt_id) {
-            prompt.purchases += 1;
+    REVIEW_VOTES.with(|rv| {
+        rv.borrow_mut().retain(|(id, _), _| *id != prompt_id);
+    });
+    USER_RATINGS.with(|ur| {
+        for ratings in ur.borrow_mut().values_mut() {
+            ratings.remove(&prompt_id);
         }
     });
-
-    // Update user stats
-    USERS.with(|u| {
-        let mut users = u.borrow_mut();
-        if let Some(buyer) = users.get_mut(&caller) {
-            buyer.prompts_purchased += 1;
-            buyer.total_spent += prompt.price;
-            if buyer.balance >= prompt.price {
-                buyer.balance -= prompt.price;
-            } else {
-                buyer.balance = 0;
-            }
+    COMMENTS.with(|c| {
+        c.borrow_mut()
+            .retain(|_, comment| comment.prompt_id != prompt_id);
+    });
+    // Purchase records stay as the ledger audit trail behind FEE_TOTALS; only the
+    // per-user id lists are pruned
+    USER_PURCHASES.with(|up| {
+        for purchases in up.borrow_mut().values_mut() {
+            purchases.retain(|id| *id != prompt_id);
         }
-        if let Some(seller) = users.get_mut(&prompt.author) {
-            seller.total_earnings += prompt.price;
-            seller.balance += prompt.price;
+    });
+    USER_LIKES.with(|ul| {
+        for likes in ul.borrow_mut().values_mut() {
+            likes.retain(|id| *id != prompt_id);
         }
     });
 
-    ApiResponse {
-        success: true,
-        data: Some("Purchase successful".to_string()),
-        error: None,
+    index_prompt(prompt_id);
+    if prompt.deleted_at.is_none() {
+        decrement_prompts_created(prompt.author);
     }
+    Ok(())
 }
 
-#[ic_cdk::query]
-fn get_prompt_content(prompt_id: PromptId) -> ApiResponse<String> {
-    let caller = get_caller();
-
-    let prompt = PROMPTS.with(|p| {
-        let prompts = p.borrow();
-        prompts.get(&prompt_id).cloned()
-    });
+fn has_pending_purchase(prompt_id: PromptId) -> bool {
+    PENDING_PURCHASES.with(|pp| pp.borrow().iter().any(|(_, id)| *id == prompt_id))
+}
 
-    match prompt {
-        Some(p) => {
-            // Allow access if: prompt is public, user is author, or user has purchased
-            if p.is_public || p.author == caller || has_purchased(caller, prompt_id) {
-                ApiResponse {
-                    success: true,
-                    data: Some(p.content),
-                    error: None,
-                }
-            } else {
-                ApiResponse {
-                    success: false,
-                    data: None,
-                    error: Some("Access denied. Purchase required.".to_string()),
-                }
+fn decrement_prompts_created(author: UserId) {
+    USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        if let Some(user) = users.get_mut(&author) {
+            if user.prompts_created > 0 {
+                user.prompts_created -= 1;
             }
         }
-        None => ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Prompt not found".to_string()),
-        },
-    }
+    });
 }
 
-#[ic_cdk::update]
-fn like_prompt(prompt_id: PromptId) -> ApiResponse<String> {
-    let caller = get_caller();
-
-    // Check if prompt exists
-    let prompt_exists = PROMPTS.with(|p| {
-        let prompts = p.borrow();
-        prompts.contains_key(&prompt_id)
+// Appends the prompt's current title, description and content as its next revision
+fn record_revision(prompt: &mut Prompt, note: Option<String>) {
+    prompt.revision += 1;
+    let revision = PromptRevision {
+        revision: prompt.revision,
+        title: prompt.title.clone(),
+        description: prompt.description.clone(),
+        content: prompt.content.clone(),
+        created_at: prompt.updated_at,
+        note,
+        variables: prompt.variables.clone(),
+        messages: prompt.messages.clone(),
+    };
+    PROMPT_REVISIONS.with(|pr| {
+        pr.borrow_mut().entry(prompt.id).or_default().push(revision);
     });
+}
 
-    if !prompt_exists {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Prompt not found".to_string()),
-        };
-    }
+// Title, description, content, variables and messages are versioned; listing fields are not
+fn has_versioned_changes(before: &Prompt, after: &Prompt) -> bool {
+    before.title != after.title
+        || before.description != after.description
+        || before.content != after.content
+        || before.variables != after.variables
+        || before.messages != after.messages
+}
 
-    // Check if already liked
-    let already_liked = USER_LIKES.with(|ul| {
-        let user_likes = ul.borrow();
-        match user_likes.get(&caller) {
-            Some(likes) => likes.contains(&prompt_id),
+fn find_revision(prompt_id: PromptId, revision: u32) -> Option<PromptRevision> {
+    PROMPT_REVISIONS.with(|pr| {
+        let revisions = pr.borrow();
+        revisions
+            .get(&prompt_id)
+            .and_then(|revisions| revisions.iter().find(|r| r.revision == revision))
+            .cloned()
+    })
+}
+
+fn has_purchased(user_id: UserId, prompt_id: PromptId) -> bool {
+    USER_PURCHASES.with(|up| {
+        let user_purchases = up.borrow();
+        match user_purchases.get(&user_id) {
+            Some(purchases) => purchases.contains(&prompt_id),
             None => false,
         }
-    });
+    })
+}
 
-    if already_liked {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Prompt already liked".to_string()),
-        };
-    }
+fn ledger_canister_id() -> Principal {
+    CONFIG.with(|c| c.borrow().ledger_canister_id)
+}
 
-    // Add like
-    USER_LIKES.with(|ul| {
-        let mut user_likes = ul.borrow_mut();
-        user_likes
-            .entry(caller)
-            .or_insert_with(Vec::new)
-            .push(prompt_id);
-    });
+// Canister controllers always count as admins, so a fresh install can't lock itself out
+fn has_role(user_id: UserId, role: &Role) -> bool {
+    ic_cdk::api::is_controller(&user_id) || has_assigned_role(user_id, role)
+}
 
-    // Update prompt likes count
-    PROMPTS.with(|p| {
-        let mut prompts = p.borrow_mut();
-        if let Some(prompt) = prompts.get_mut(&prompt_id) {
-            prompt.likes += 1;
+// Role granted through grant_role or the init args, leaving controllers aside
+fn has_assigned_role(user_id: UserId, role: &Role) -> bool {
+    ROLES.with(|r| {
+        let roles = r.borrow();
+        match roles.get(&user_id).map(|assignment| &assignment.role) {
+            Some(Role::Admin) => true,
+            Some(Role::Moderator) => *role == Role::Moderator,
+            None => false,
         }
-    });
+    })
+}
 
-    ApiResponse {
-        success: true,
-        data: Some("Prompt liked successfully".to_string()),
-        error: None,
+// Guard for privileged endpoints; hands back the caller when they hold the role.
+// Controllers are exempt from the status check so the canister can't be locked out.
+fn require_role(role: Role) -> Result<UserId, PromptVaultError> {
+    let caller = get_caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        check_assigned_role(caller, &role, get_time())?;
     }
+    Ok(caller)
 }
 
-#[ic_cdk::update]
-fn unlike_prompt(prompt_id: PromptId) -> ApiResponse<String> {
-    let caller = get_caller();
+// A suspended or banned account can't use the role it was granted
+fn check_assigned_role(user_id: UserId, role: &Role, now: u64) -> Result<(), PromptVaultError> {
+    if !has_assigned_role(user_id, role) {
+        return Err(PromptVaultError::Unauthorized);
+    }
+    check_active(user_id, now)
+}
 
-    // Check if prompt exists
-    let prompt_exists = PROMPTS.with(|p| {
-        let prompts = p.borrow();
-        prompts.contains_key(&prompt_id)
+fn assign_role(user_id: UserId, role: Role, granted_by: UserId) -> RoleAssignment {
+    let assignment = RoleAssignment {
+        user_id,
+        role,
+        granted_by,
+        granted_at: get_time(),
+    };
+    ROLES.with(|r| {
+        r.borrow_mut().insert(user_id, assignment.clone());
     });
+    assignment
+}
 
-    if !prompt_exists {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Prompt not found".to_string()),
-        };
+fn apply_init_args(args: InitArgs) -> Result<(), PromptVaultError> {
+    if let Some(update) = args.config {
+        apply_config_update(update)?;
+    }
+    for admin in args.admins.unwrap_or_default() {
+        assign_role(admin, Role::Admin, get_caller());
     }
+    Ok(())
+}
 
-    // Check if actually liked
-    let was_liked = USER_LIKES.with(|ul| {
-        let mut user_likes = ul.borrow_mut();
-        match user_likes.get_mut(&caller) {
-            Some(likes) => {
-                if let Some(pos) = likes.iter().position(|&x| x == prompt_id) {
-                    likes.remove(pos);
-                    true
-                } else {
-                    false
-                }
-            }
-            None => false,
+fn apply_config_update(update: ConfigUpdate) -> Result<Config, PromptVaultError> {
+    if let Some(bps) = update.platform_fee_bps {
+        if bps > MAX_PLATFORM_FEE_BPS {
+            return Err(PromptVaultError::validation(
+                "platform_fee_bps",
+                format!(
+                    "Platform fee cannot exceed {} basis points",
+                    MAX_PLATFORM_FEE_BPS
+                ),
+            ));
         }
-    });
-
-    if !was_liked {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Prompt was not liked".to_string()),
-        };
     }
 
-    // Update prompt likes count
-    PROMPTS.with(|p| {
-        let mut prompts = p.borrow_mut();
-        if let Some(prompt) = prompts.get_mut(&prompt_id) {
-            if prompt.likes > 0 {
-                prompt.likes -= 1;
-            }
+    let policy_changed = update
+        .rating_eligibility
+        .as_ref()
+        .is_some_and(|policy| CONFIG.with(|c| c.borrow().rating_eligibility != *policy));
+    let config = CONFIG.with(|c| {
+        let mut config = c.borrow_mut();
+        if let Some(ledger_canister_id) = update.ledger_canister_id {
+            config.ledger_canister_id = ledger_canister_id;
         }
+        if let Some(index_canister_id) = update.index_canister_id {
+            config.index_canister_id = Some(index_canister_id);
+        }
+        if let Some(platform_fee_bps) = update.platform_fee_bps {
+            config.platform_fee_bps = platform_fee_bps;
+        }
+        if let Some(treasury) = update.treasury {
+            config.treasury = Some(treasury);
+        }
+        if let Some(rating_eligibility) = update.rating_eligibility {
+            config.rating_eligibility = rating_eligibility;
+        }
+        config.clone()
     });
-
-    ApiResponse {
-        success: true,
-        data: Some("Prompt unliked successfully".to_string()),
-        error: None,
+    // Existing ratings are recounted so the aggregates always reflect the active policy
+    if policy_changed {
+        recount_ratings();
     }
+    Ok(config)
 }
 
-#[ic_cdk::update]
-fn rate_prompt(request: RatePromptRequest) -> ApiResponse<String> {
-    let caller = get_caller();
-
-    // Validate rating
-    if let Err(error) = validate_rating(request.rating) {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some(error),
-        };
-    }
+// Platform cut of a sale at the configured rate, rounded down in the seller's favour
+fn platform_fee(price: u64) -> u64 {
+    let bps = CONFIG.with(|c| c.borrow().platform_fee_bps);
+    (price as u128 * bps as u128 / MAX_PLATFORM_FEE_BPS as u128) as u64
+}
 
-    // Check if prompt exists
-    let prompt_exists = PROMPTS.with(|p| {
-        let prompts = p.borrow();
-        prompts.contains_key(&request.prompt_id)
-    });
+// Subaccount of this canister that holds the given user's deposit
+fn deposit_subaccount(user_id: UserId) -> Vec<u8> {
+    let bytes = user_id.as_slice();
+    let mut subaccount = vec![0u8; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
 
-    if !prompt_exists {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Prompt not found".to_string()),
-        };
+// Main account of this canister; holds swept deposits and unpaid seller earnings
+fn escrow_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: None,
     }
+}
 
-    // Check if user owns the prompt
-    if is_authorized(request.prompt_id, caller) {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Cannot rate your own prompt".to_string()),
-        };
+fn deposit_account(user_id: UserId) -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(deposit_subaccount(user_id)),
     }
+}
 
-    // Check if user has purchased the prompt or it's public
-    let can_rate = PROMPTS.with(|p| {
-        let prompts = p.borrow();
-        if let Some(prompt) = prompts.get(&request.prompt_id) {
-            prompt.is_public || has_purchased(caller, request.prompt_id)
-        } else {
-            false
-        }
-    });
-
-    if !can_rate {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Must purchase prompt to rate it".to_string()),
-        };
-    }
+// Clamps at u64::MAX; for error reports and for block indices of transfers that already
+// went through, where failing would lose track of the moved funds
+fn nat_to_u64(value: &Nat) -> u64 {
+    u64::try_from(&value.0).unwrap_or(u64::MAX)
+}
 
-    // Record or update the rating
-    let previous_rating = USER_RATINGS.with(|ur| {
-        let mut user_ratings = ur.borrow_mut();
-        user_ratings
-            .entry(caller)
-            .or_insert_with(HashMap::new)
-            .insert(request.prompt_id, request.rating)
-    });
+// For balances and fees read before a transfer, where an out of range value is refused
+fn checked_nat_to_u64(value: &Nat) -> Result<u64, PaymentError> {
+    u64::try_from(&value.0).map_err(|_| PaymentError::GenericError {
+        error_code: 0,
+        message: format!("Ledger amount {} does not fit in u64", value),
+    })
+}
 
-    // Update prompt rating
-    PROMPTS.with(|p| {
-        let mut prompts = p.borrow_mut();
-        if let Some(prompt) = prompts.get_mut(&request.prompt_id) {
-            if previous_rating.is_none() {
-                // New rating
-                prompt.total_ratings += 1;
-                let total_score =
-                    prompt.rating * (prompt.total_ratings - 1) as f64 + request.rating as f64;
-                prompt.rating = safe_f64_average(total_score, prompt.total_ratings);
-            } else {
-                // Update existing rating
-                let old_rating = previous_rating.unwrap() as f64;
-                let total_score = prompt.rating * prompt.total_ratings as f64 - old_rating
-                    + request.rating as f64;
-                prompt.rating = safe_f64_average(total_score, prompt.total_ratings);
+impl From<TransferError> for PaymentError {
+    fn from(error: TransferError) -> Self {
+        match error {
+            TransferError::BadFee { expected_fee } => PaymentError::BadFee {
+                expected_fee: nat_to_u64(&expected_fee),
+            },
+            TransferError::BadBurn { min_burn_amount } => PaymentError::BadBurn {
+                min_burn_amount: nat_to_u64(&min_burn_amount),
+            },
+            TransferError::InsufficientFunds { balance } => PaymentError::InsufficientFunds {
+                balance: nat_to_u64(&balance),
+            },
+            TransferError::TooOld => PaymentError::TooOld,
+            TransferError::CreatedInFuture { ledger_time } => {
+                PaymentError::CreatedInFuture { ledger_time }
             }
+            TransferError::TemporarilyUnavailable => PaymentError::TemporarilyUnavailable,
+            TransferError::Duplicate { duplicate_of } => PaymentError::Duplicate {
+                duplicate_of: nat_to_u64(&duplicate_of),
+            },
+            TransferError::GenericError {
+                error_code,
+                message,
+            } => PaymentError::GenericError {
+                error_code: nat_to_u64(&error_code),
+                message,
+            },
         }
-    });
-
-    ApiResponse {
-        success: true,
-        data: Some("Prompt rated successfully".to_string()),
-        error: None,
     }
 }
 
-#[ic_cdk::query]
-fn get_user_purchases(user_id: UserId) -> ApiResponse<Vec<PromptId>> {
-    USER_PURCHASES.with(|up| {
-        let user_purchases = up.borrow();
-        match user_purchases.get(&user_id) {
-            Some(purchases) => ApiResponse {
-                success: true,
-                data: Some(purchases.clone()),
-                error: None,
+impl From<TransferFromError> for PaymentError {
+    fn from(error: TransferFromError) -> Self {
+        match error {
+            TransferFromError::BadFee { expected_fee } => PaymentError::BadFee {
+                expected_fee: nat_to_u64(&expected_fee),
             },
-            None => ApiResponse {
-                success: true,
-                data: Some(Vec::new()),
-                error: None,
+            TransferFromError::BadBurn { min_burn_amount } => PaymentError::BadBurn {
+                min_burn_amount: nat_to_u64(&min_burn_amount),
+            },
+            TransferFromError::InsufficientFunds { balance } => PaymentError::InsufficientFunds {
+                balance: nat_to_u64(&balance),
+            },
+            TransferFromError::InsufficientAllowance { allowance } => {
+                PaymentError::InsufficientAllowance {
+                    allowance: nat_to_u64(&allowance),
+                }
+            }
+            TransferFromError::TooOld => PaymentError::TooOld,
+            TransferFromError::CreatedInFuture { ledger_time } => {
+                PaymentError::CreatedInFuture { ledger_time }
+            }
+            TransferFromError::Duplicate { duplicate_of } => PaymentError::Duplicate {
+                duplicate_of: nat_to_u64(&duplicate_of),
+            },
+            TransferFromError::TemporarilyUnavailable => PaymentError::TemporarilyUnavailable,
+            TransferFromError::GenericError {
+                error_code,
+                message,
+            } => PaymentError::GenericError {
+                error_code: nat_to_u64(&error_code),
+                message,
             },
         }
-    })
+    }
 }
 
-#[query]
-fn search_prompts(query: String, category: Option<PromptCategory>) -> ApiResponse<Vec<Prompt>> {
-    PROMPTS.with(|p| {
-        let prompts = p.borrow();
-        let mut results: Vec<Prompt> = prompts
-            .values()
-            .filter(|prompt| {
-                // Only search public prompts
-                if !prompt.is_public {
-                    return false;
-                }
+impl std::fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaymentError::BadFee { expected_fee } => {
+                write!(f, "Bad fee, ledger expects {}", expected_fee)
+            }
+            PaymentError::BadBurn { min_burn_amount } => {
+                write!(f, "Bad burn, minimum is {}", min_burn_amount)
+            }
+            PaymentError::InsufficientFunds { balance } => {
+                write!(f, "Insufficient funds, balance is {}", balance)
+            }
+            PaymentError::InsufficientAllowance { allowance } => {
+                write!(f, "Insufficient allowance, allowance is {}", allowance)
+            }
+            PaymentError::TooOld => write!(f, "Transaction too old"),
+            PaymentError::CreatedInFuture { ledger_time } => write!(
+                f,
+                "Transaction created in the future, ledger time is {}",
+                ledger_time
+            ),
+            PaymentError::Duplicate { duplicate_of } => {
+                write!(f, "Duplicate of transaction {}", duplicate_of)
+            }
+            PaymentError::TemporarilyUnavailable => write!(f, "Ledger temporarily unavailable"),
+            PaymentError::GenericError {
+                error_code,
+                message,
+            } => write!(f, "Ledger error {}: {}", error_code, message),
+            PaymentError::CallFailed { message } => {
+                write!(f, "Failed to call ledger: {}", message)
+            }
+        }
+    }
+}
 
-                // Category filter
-                if let Some(ref cat) = category {
-                    if prompt.category != *cat {
-                        return false;
-                    }
-                }
+impl From<PaymentError> for PromptVaultError {
+    fn from(error: PaymentError) -> Self {
+        PromptVaultError::Ledger(error)
+    }
+}
 
-                // Text search in title, description, and tags
-                let query_lower = query.to_lowercase();
-                prompt.title.to_lowercase().contains(&query_lower)
-                    || prompt.description.to_lowercase().contains(&query_lower)
-                    || prompt
-                        .tags
-                        .iter()
-                        .any(|tag| tag.to_lowercase().contains(&query_lower))
-            })
-            .cloned()
-            .collect();
+impl std::fmt::Display for PromptVaultError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PromptVaultError::NotFound { resource } => write!(f, "{} not found", resource),
+            PromptVaultError::Unauthorized => write!(f, "Unauthorized"),
+            PromptVaultError::Forbidden { reason } => write!(f, "{}", reason),
+            PromptVaultError::AlreadyExists { resource } => {
+                write!(f, "{} already exists", resource)
+            }
+            PromptVaultError::AlreadyPurchased => write!(f, "Prompt already purchased"),
+            PromptVaultError::Conflict { reason } => write!(f, "{}", reason),
+            PromptVaultError::OperationInProgress => {
+                write!(f, "Another operation is in progress, try again shortly")
+            }
+            PromptVaultError::Validation { reason, .. } => write!(f, "{}", reason),
+            PromptVaultError::InsufficientFunds {
+                required,
+                available,
+            } => write!(
+                f,
+                "Insufficient funds: {} e8s required (including fee), {} e8s available",
+                required, available
+            ),
+            PromptVaultError::Ledger(error) => write!(f, "{}", error),
+            PromptVaultError::AccountRestricted { status } => match status {
+                UserStatus::Suspended { until } => {
+                    write!(f, "Account is suspended until {}", until)
+                }
+                UserStatus::Banned => write!(f, "Account is banned"),
+                UserStatus::Active => write!(f, "Account is restricted"),
+            },
+        }
+    }
+}
+
+// Only the variants purchase_prompt can return have a dedicated counterpart
+impl From<PromptVaultError> for PurchaseError {
+    fn from(error: PromptVaultError) -> Self {
+        match error {
+            PromptVaultError::NotFound { .. } => PurchaseError::PromptNotFound,
+            PromptVaultError::Forbidden { .. } => PurchaseError::OwnPrompt,
+            PromptVaultError::AlreadyPurchased => PurchaseError::AlreadyPurchased,
+            PromptVaultError::OperationInProgress => PurchaseError::PurchaseInProgress,
+            PromptVaultError::InsufficientFunds { available, .. } => {
+                PurchaseError::Payment(PaymentError::InsufficientFunds { balance: available })
+            }
+            PromptVaultError::Ledger(error) => PurchaseError::Payment(error),
+            error => PurchaseError::Payment(PaymentError::GenericError {
+                error_code: 0,
+                message: error.to_string(),
+            }),
+        }
+    }
+}
+
+fn call_failed(error: (ic_cdk::api::call::RejectionCode, String)) -> PaymentError {
+    PaymentError::CallFailed {
+        message: format!("{:?}", error),
+    }
+}
+
+async fn ledger_balance_of(account: Account) -> Result<u64, PaymentError> {
+    let (balance,) =
+        ic_cdk::call::<(Account,), (Nat,)>(ledger_canister_id(), "icrc1_balance_of", (account,))
+            .await
+            .map_err(call_failed)?;
+    checked_nat_to_u64(&balance)
+}
+
+async fn ledger_fee() -> Result<u64, PaymentError> {
+    let (fee,) = ic_cdk::call::<(), (Nat,)>(ledger_canister_id(), "icrc1_fee", ())
+        .await
+        .map_err(call_failed)?;
+    checked_nat_to_u64(&fee)
+}
+
+async fn ledger_transfer(arg: TransferArg) -> Result<u64, PaymentError> {
+    let (result,) = ic_cdk::call::<(TransferArg,), (Result<Nat, TransferError>,)>(
+        ledger_canister_id(),
+        "icrc1_transfer",
+        (arg,),
+    )
+    .await
+    .map_err(call_failed)?;
+    result
+        .map(|block_index| nat_to_u64(&block_index))
+        .map_err(PaymentError::from)
+}
+
+async fn ledger_transfer_from(args: TransferFromArgs) -> Result<u64, PaymentError> {
+    let (result,) = ic_cdk::call::<(TransferFromArgs,), (Result<Nat, TransferFromError>,)>(
+        ledger_canister_id(),
+        "icrc2_transfer_from",
+        (args,),
+    )
+    .await
+    .map_err(call_failed)?;
+    result
+        .map(|block_index| nat_to_u64(&block_index))
+        .map_err(PaymentError::from)
+}
+
+// Holds the (buyer, prompt) slot while a purchase awaits the ledger
+struct PurchaseGuard {
+    key: (UserId, PromptId),
+}
+
+impl PurchaseGuard {
+    fn new(buyer: UserId, prompt_id: PromptId) -> Result<Self, PromptVaultError> {
+        let key = (buyer, prompt_id);
+        PENDING_PURCHASES.with(|pp| {
+            if pp.borrow_mut().insert(key) {
+                Ok(PurchaseGuard { key })
+            } else {
+                Err(PromptVaultError::OperationInProgress)
+            }
+        })
+    }
+}
+
+impl Drop for PurchaseGuard {
+    fn drop(&mut self) {
+        PENDING_PURCHASES.with(|pp| {
+            pp.borrow_mut().remove(&self.key);
+        });
+    }
+}
+
+// Holds the user's internal balance while a deposit sync or withdrawal awaits the ledger
+struct BalanceGuard {
+    user_id: UserId,
+}
 
-        // Sort by relevance (likes + purchases + rating)
-        results.sort_by(|a, b| {
-            let score_a = a.likes + a.purchases + (a.rating * 10.0) as u64;
-            let score_b = b.likes + b.purchases + (b.rating * 10.0) as u64;
-            score_b.cmp(&score_a)
+impl BalanceGuard {
+    fn new(user_id: UserId) -> Result<Self, PromptVaultError> {
+        PENDING_BALANCE_OPS.with(|pb| {
+            if pb.borrow_mut().insert(user_id) {
+                Ok(BalanceGuard { user_id })
+            } else {
+                Err(PromptVaultError::OperationInProgress)
+            }
+        })
+    }
+}
+
+impl Drop for BalanceGuard {
+    fn drop(&mut self) {
+        PENDING_BALANCE_OPS.with(|pb| {
+            pb.borrow_mut().remove(&self.user_id);
         });
+    }
+}
+
+enum TemplateSegment<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    name.len() <= MAX_VARIABLE_NAME_LENGTH
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Splits content into literal text and `{{name}}` placeholders; braces around anything
+// that isn't a variable name are left as literal text
+fn template_segments(content: &str) -> Vec<TemplateSegment<'_>> {
+    let mut segments = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) if is_variable_name(after[..end].trim()) => {
+                segments.push(TemplateSegment::Text(&rest[..start]));
+                segments.push(TemplateSegment::Placeholder(after[..end].trim()));
+                rest = &after[end + 2..];
+            }
+            _ => {
+                segments.push(TemplateSegment::Text(&rest[..start + 2]));
+                rest = after;
+            }
+        }
+    }
+    segments.push(TemplateSegment::Text(rest));
+    segments
+}
+
+// Without declared variables the content is plain text; once declared, the placeholders
+// and the variables must match one to one
+fn validate_variables(
+    content: &str,
+    variables: &[TemplateVariable],
+) -> Result<(), PromptVaultError> {
+    if variables.is_empty() {
+        return Ok(());
+    }
+    if variables.len() > MAX_VARIABLES {
+        return Err(PromptVaultError::validation(
+            "variables",
+            format!("Cannot have more than {} variables", MAX_VARIABLES),
+        ));
+    }
+
+    let mut declared: HashSet<&str> = HashSet::new();
+    for variable in variables {
+        if !is_variable_name(&variable.name) {
+            return Err(PromptVaultError::validation(
+                "variables",
+                format!("Invalid variable name \"{}\"", variable.name),
+            ));
+        }
+        if !declared.insert(&variable.name) {
+            return Err(PromptVaultError::validation(
+                "variables",
+                format!("Variable {} is declared twice", variable.name),
+            ));
+        }
+        if variable
+            .description
+            .as_ref()
+            .is_some_and(|description| description.len() > MAX_VARIABLE_DESCRIPTION_LENGTH)
+        {
+            return Err(PromptVaultError::validation(
+                "variables",
+                format!(
+                    "Variable description cannot exceed {} characters",
+                    MAX_VARIABLE_DESCRIPTION_LENGTH
+                ),
+            ));
+        }
+        if let Some(ref options) = variable.options {
+            if options.is_empty() || options.len() > MAX_VARIABLE_OPTIONS {
+                return Err(PromptVaultError::validation(
+                    "variables",
+                    format!(
+                        "Variable {} must have between 1 and {} options",
+                        variable.name, MAX_VARIABLE_OPTIONS
+                    ),
+                ));
+            }
+        }
+        if let Some(ref default_value) = variable.default_value {
+            validate_variable_value(variable, default_value)?;
+        }
+    }
+
+    let mut used: HashSet<&str> = HashSet::new();
+    for segment in template_segments(content) {
+        if let TemplateSegment::Placeholder(name) = segment {
+            if !declared.contains(name) {
+                return Err(PromptVaultError::validation(
+                    "content",
+                    format!("Placeholder {{{{{}}}}} is not a declared variable", name),
+                ));
+            }
+            used.insert(name);
+        }
+    }
+    if let Some(unused) = variables.iter().find(|v| !used.contains(v.name.as_str())) {
+        return Err(PromptVaultError::validation(
+            "variables",
+            format!("Variable {} is not used in the content", unused.name),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_variable_value(
+    variable: &TemplateVariable,
+    value: &str,
+) -> Result<(), PromptVaultError> {
+    if value.len() > MAX_VARIABLE_VALUE_LENGTH {
+        return Err(PromptVaultError::validation(
+            &variable.name,
+            format!(
+                "Value cannot exceed {} characters",
+                MAX_VARIABLE_VALUE_LENGTH
+            ),
+        ));
+    }
+    if let Some(ref options) = variable.options {
+        if !options.iter().any(|option| option == value) {
+            return Err(PromptVaultError::validation(
+                &variable.name,
+                format!("Value must be one of: {}", options.join(", ")),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Single-text form of a chain, one "Role:" block per message
+fn flatten_messages(messages: &[PromptMessage]) -> String {
+    messages
+        .iter()
+        .map(|message| {
+            let role = match message.role {
+                MessageRole::System => "System",
+                MessageRole::User => "User",
+                MessageRole::Assistant => "Assistant",
+            };
+            format!("{}:\n{}", role, message.text)
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn validate_messages(messages: &[PromptMessage]) -> Result<(), PromptVaultError> {
+    if messages.len() > MAX_MESSAGES {
+        return Err(PromptVaultError::validation(
+            "messages",
+            format!("Cannot have more than {} messages", MAX_MESSAGES),
+        ));
+    }
+    for (position, message) in messages.iter().enumerate() {
+        if message.text.trim().is_empty() {
+            return Err(PromptVaultError::validation(
+                "messages",
+                format!("Message {} cannot be empty", position + 1),
+            ));
+        }
+        if message.role == MessageRole::System && position > 0 {
+            return Err(PromptVaultError::validation(
+                "messages",
+                "Only the first message can be a system message",
+            ));
+        }
+    }
+    if flatten_messages(messages).len() > MAX_CONTENT_LENGTH {
+        return Err(PromptVaultError::validation(
+            "messages",
+            format!(
+                "Messages cannot exceed {} characters in total",
+                MAX_CONTENT_LENGTH
+            ),
+        ));
+    }
+    Ok(())
+}
 
-        ApiResponse {
-            success: true,
-            data: Some(results),
-            error: None,
+fn validate_prompt_input(request: &CreatePromptRequest) -> Result<(), PromptVaultError> {
+    if request.title.trim().is_empty() {
+        return Err(PromptVaultError::validation(
+            "title",
+            "Title cannot be empty",
+        ));
+    }
+    if request.title.len() > MAX_TITLE_LENGTH {
+        return Err(PromptVaultError::validation(
+            "title",
+            format!("Title cannot exceed {} characters", MAX_TITLE_LENGTH),
+        ));
+    }
+    if request.description.len() > MAX_DESCRIPTION_LENGTH {
+        return Err(PromptVaultError::validation(
+            "description",
+            format!(
+                "Description cannot exceed {} characters",
+                MAX_DESCRIPTION_LENGTH
+            ),
+        ));
+    }
+    if request.content.trim().is_empty() {
+        return Err(PromptVaultError::validation(
+            "content",
+            "Content cannot be empty",
+        ));
+    }
+    if request.content.len() > MAX_CONTENT_LENGTH {
+        return Err(PromptVaultError::validation(
+            "content",
+            format!("Content cannot exceed {} characters", MAX_CONTENT_LENGTH),
+        ));
+    }
+    if request.tags.len() > MAX_TAGS {
+        return Err(PromptVaultError::validation(
+            "tags",
+            format!("Cannot have more than {} tags", MAX_TAGS),
+        ));
+    }
+    for tag in &request.tags {
+        if tag.len() > MAX_TAG_LENGTH {
+            return Err(PromptVaultError::validation(
+                "tags",
+                format!("Tag cannot exceed {} characters", MAX_TAG_LENGTH),
+            ));
         }
+    }
+    validate_pricing(request.is_premium, request.price)?;
+    validate_variables(
+        &request.content,
+        request.variables.as_deref().unwrap_or_default(),
+    )?;
+    Ok(())
+}
+
+fn validate_pricing(is_premium: bool, price: u64) -> Result<(), PromptVaultError> {
+    if is_premium && price == 0 {
+        return Err(PromptVaultError::validation(
+            "price",
+            "Premium prompts must have a price",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_rating(rating: u8) -> Result<(), PromptVaultError> {
+    if !(1..=5).contains(&rating) {
+        return Err(PromptVaultError::validation(
+            "rating",
+            "Rating must be between 1 and 5",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_changelog(note: Option<&str>) -> Result<(), PromptVaultError> {
+    if note.is_some_and(|note| note.len() > MAX_CHANGELOG_LENGTH) {
+        return Err(PromptVaultError::validation(
+            "changelog",
+            format!(
+                "Changelog note cannot exceed {} characters",
+                MAX_CHANGELOG_LENGTH
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Trims review and reply text; a blank one comes back as None
+fn validate_review_text(
+    field: &str,
+    text: &str,
+    max_length: usize,
+) -> Result<Option<String>, PromptVaultError> {
+    let text = text.trim();
+    if text.len() > max_length {
+        return Err(PromptVaultError::validation(
+            field,
+            format!("Text cannot exceed {} characters", max_length),
+        ));
+    }
+    Ok((!text.is_empty()).then(|| text.to_string()))
+}
+
+fn check_rating_eligibility(
+    user_id: UserId,
+    verified_purchase: bool,
+    rated_at: u64,
+) -> Result<(), PromptVaultError> {
+    let policy = CONFIG.with(|c| c.borrow().rating_eligibility.clone());
+    if rating_counts_under(&policy, user_id, verified_purchase, rated_at) {
+        return Ok(());
+    }
+    Err(match policy {
+        RatingEligibility::MinAccountAge { seconds } => PromptVaultError::forbidden(format!(
+            "Accounts must be at least {} seconds old to rate prompts",
+            seconds
+        )),
+        _ => PromptVaultError::forbidden("Only buyers can rate prompts"),
     })
 }
 
+// Whether a rating counts towards the aggregates under the policy. It is judged as of
+// when it was made, so recounting after a policy change always gives the same result
+fn rating_counts_under(
+    policy: &RatingEligibility,
+    user_id: UserId,
+    verified_purchase: bool,
+    rated_at: u64,
+) -> bool {
+    match policy {
+        RatingEligibility::Anyone => true,
+        RatingEligibility::Buyers => verified_purchase,
+        RatingEligibility::MinAccountAge { seconds } => USERS.with(|u| {
+            u.borrow().get(&user_id).is_some_and(|user| {
+                rated_at.saturating_sub(user.joined_at) >= seconds.saturating_mul(1_000_000_000)
+            })
+        }),
+    }
+}
+
+// The user's rating of a prompt if it is counted under the active policy
+fn counted_rating(user_id: UserId, prompt_id: PromptId) -> Option<u8> {
+    let rating = USER_RATINGS.with(|ur| ur.borrow().get(&user_id)?.get(&prompt_id).copied())?;
+    let policy = CONFIG.with(|c| c.borrow().rating_eligibility.clone());
+    let counts = REVIEWS.with(
+        |r| match r.borrow().get(&prompt_id).and_then(|r| r.get(&user_id)) {
+            Some(review) => rating_counts_under(
+                &policy,
+                user_id,
+                review.verified_purchase,
+                review.updated_at,
+            ),
+            None => policy == RatingEligibility::Anyone,
+        },
+    );
+    counts.then_some(rating)
+}
+
+// Rebuilds every prompt's rating aggregates from the stored ratings the active policy
+// counts; ineligible ratings stay stored so a later policy change can count them again
+fn recount_ratings() {
+    PROMPTS.with(|p| {
+        for prompt in p.borrow_mut().values_mut() {
+            prompt.rating_sum = 0;
+            prompt.rating_counts = [0; 5];
+            update_rating_aggregates(prompt, None, None);
+        }
+    });
+    let ratings: Vec<(UserId, PromptId)> = USER_RATINGS.with(|ur| {
+        ur.borrow()
+            .iter()
+            .flat_map(|(user_id, ratings)| ratings.keys().map(move |id| (*user_id, *id)))
+            .collect()
+    });
+    for (user_id, prompt_id) in ratings {
+        if let Some(rating) = counted_rating(user_id, prompt_id) {
+            PROMPTS.with(|p| {
+                if let Some(prompt) = p.borrow_mut().get_mut(&prompt_id) {
+                    update_rating_aggregates(prompt, None, Some(rating));
+                }
+            });
+        }
+    }
+}
+
+// Comments can only be posted and read while the prompt is listed
+fn require_comments_open(prompt_id: PromptId) -> Result<(), PromptVaultError> {
+    match PROMPTS.with(|p| p.borrow().get(&prompt_id).map(is_listed)) {
+        Some(true) => Ok(()),
+        Some(false) => Err(PromptVaultError::forbidden(
+            "Comments are only available on public prompts",
+        )),
+        None => Err(PromptVaultError::not_found("Prompt")),
+    }
+}
+
+// Live comments only; deleted ones are placeholders that can't be edited or replied to
+fn find_comment(comment_id: CommentId) -> Result<Comment, PromptVaultError> {
+    COMMENTS
+        .with(|c| c.borrow().get(&comment_id).cloned())
+        .filter(|comment| comment.deleted_at.is_none())
+        .ok_or_else(|| PromptVaultError::not_found("Comment"))
+}
+
+// Empties the comment, keeping it as a placeholder while it has replies; placeholders
+// left without replies are dropped, walking up the thread
+fn discard_comment(comment_id: CommentId, by_moderator: bool) {
+    COMMENTS.with(|c| {
+        let mut comments = c.borrow_mut();
+        let Some(comment) = comments.get_mut(&comment_id) else {
+            return;
+        };
+        comment.text.clear();
+        comment.deleted_at.get_or_insert(get_time());
+        comment.removed_by_moderator |= by_moderator;
+
+        let mut id = comment_id;
+        while let Some(comment) = comments.get(&id) {
+            if comment.deleted_at.is_none() || comment.reply_count > 0 {
+                break;
+            }
+            let parent_id = comment.parent_id;
+            comments.remove(&id);
+            match parent_id.and_then(|parent_id| comments.get_mut(&parent_id)) {
+                Some(parent) => {
+                    parent.reply_count = parent.reply_count.saturating_sub(1);
+                    id = parent.id;
+                }
+                None => break,
+            }
+        }
+    });
+}
+
+// Reviews can be read wherever the prompt is listed, and by its author and buyers
+fn check_reviews_visible(prompt_id: PromptId, caller: UserId) -> Result<(), PromptVaultError> {
+    let visible = PROMPTS.with(|p| {
+        p.borrow().get(&prompt_id).map(|prompt| {
+            is_listed(prompt) || prompt.author == caller || has_purchased(caller, prompt_id)
+        })
+    });
+    match visible {
+        Some(true) => Ok(()),
+        Some(false) => Err(PromptVaultError::forbidden(
+            "Reviews of this prompt are not public",
+        )),
+        None => Err(PromptVaultError::not_found("Prompt")),
+    }
+}
+
+// Returns the trimmed text to store
+fn validate_comment(text: &str) -> Result<String, PromptVaultError> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(PromptVaultError::validation(
+            "text",
+            "Comment cannot be empty",
+        ));
+    }
+    if text.len() > MAX_COMMENT_LENGTH {
+        return Err(PromptVaultError::validation(
+            "text",
+            format!("Comment cannot exceed {} characters", MAX_COMMENT_LENGTH),
+        ));
+    }
+    Ok(text.to_string())
+}
+
+fn validate_username(username: &str) -> Result<(), PromptVaultError> {
+    if username.trim().is_empty() || username.len() > 50 {
+        return Err(PromptVaultError::validation(
+            "username",
+            "Username must be between 1 and 50 characters",
+        ));
+    }
+    Ok(())
+}
+
+fn safe_f64_average(total: f64, count: u64) -> f64 {
+    if count == 0 {
+        0.0
+    } else {
+        let avg = total / count as f64;
+        if avg.is_finite() {
+            avg
+        } else {
+            0.0
+        }
+    }
+}
+
+// Public functions
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    if let Some(args) = args {
+        apply_init_args(args).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()));
+    }
+    ic_cdk::println!("Prompt Vault initialized");
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    let state = take_state();
+    let mut writer = BufferedStableWriter::new(STABLE_BUFFER_SIZE);
+    ciborium::into_writer(&state, &mut writer)
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to save stable state: {}", e)));
+    writer
+        .flush()
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to flush stable state: {}", e)));
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    let reader = BufferedStableReader::new(STABLE_BUFFER_SIZE);
+    let state =
+        load_state(ic_cdk::api::stable::stable_size(), reader).unwrap_or_else(|e| ic_cdk::trap(&e));
+    restore_state(state).unwrap_or_else(|e| ic_cdk::trap(&e));
+    if let Some(args) = args {
+        apply_init_args(args).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()));
+    }
+    ic_cdk::println!("Prompt Vault upgraded");
+}
+
+#[update]
+fn create_user(username: Option<String>, email: Option<String>) -> Result<User, PromptVaultError> {
+    let caller = get_caller();
+
+    // Check if user already exists
+    let user_exists = USERS.with(|u| {
+        let users = u.borrow();
+        users.contains_key(&caller)
+    });
+
+    if user_exists {
+        return Err(PromptVaultError::AlreadyExists {
+            resource: "User".to_string(),
+        });
+    }
+
+    // Validate username if provided
+    if let Some(ref name) = username {
+        validate_username(name)?;
+    }
+
+    let user = User {
+        id: caller,
+        username,
+        email,
+        joined_at: get_time(),
+        total_earnings: 0,
+        total_spent: 0,
+        prompts_created: 0,
+        prompts_purchased: 0,
+        balance: 0,
+        status: UserStatus::Active,
+        warnings: 0,
+        follower_count: 0,
+        following_count: 0,
+    };
+
+    USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        users.insert(caller, user.clone());
+    });
+
+    Ok(user)
+}
+
 #[ic_cdk::query]
-fn get_user_balance(user_id: UserId) -> ApiResponse<u64> {
+fn get_user(user_id: UserId) -> Result<User, PromptVaultError> {
     USERS.with(|u| {
         let users = u.borrow();
-        match users.get(&user_id) {
-            Some(user) => ApiResponse {
-                success: true,
-                data: Some(user.balance),
-                error: None,
-            },
-            None => ApiResponse {
-                success: false,
-                data: None,
-                error: Some("User not found".to_string()),
-            },
+        users
+            .get(&user_id)
+            .cloned()
+            .ok_or_else(|| PromptVaultError::not_found("User"))
+    })
+}
+
+#[ic_cdk::update]
+fn create_prompt(mut request: CreatePromptRequest) -> Result<Prompt, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    // Canonicalize tags first so the limits below apply to what gets stored
+    request.tags = normalize_tags(&request.tags)
+        .map_err(|reason| PromptVaultError::validation("tags", reason))?;
+
+    // Chains are stored alongside their flattened form, which the checks below apply to
+    if let Some(ref messages) = request.messages {
+        if !messages.is_empty() {
+            validate_messages(messages)?;
+            request.content = flatten_messages(messages);
+        }
+    }
+
+    // Validate input
+    validate_prompt_input(&request)?;
+
+    // Ensure user exists
+    let user_exists = USERS.with(|u| {
+        let users = u.borrow();
+        users.contains_key(&caller)
+    });
+
+    if !user_exists {
+        return Err(PromptVaultError::not_found("User"));
+    }
+
+    let prompt_id = NEXT_PROMPT_ID.with(|id| {
+        let mut next_id = id.borrow_mut();
+        let current_id = *next_id;
+        *next_id += 1;
+        current_id
+    });
+
+    let now = get_time();
+    let mut prompt = Prompt {
+        id: prompt_id,
+        title: request.title.trim().to_string(),
+        description: request.description,
+        content: request.content,
+        author: caller,
+        category: request.category,
+        tags: request.tags,
+        price: request.price,
+        is_premium: request.is_premium,
+        is_public: request.is_public,
+        created_at: now,
+        updated_at: now,
+        likes: 0,
+        purchases: 0,
+        rating: 0.0,
+        total_ratings: 0,
+        rating_sum: 0,
+        rating_counts: [0; 5],
+        deleted_at: None,
+        revision: 0,
+        variables: request.variables.unwrap_or_default(),
+        messages: request.messages.unwrap_or_default(),
+        hidden_at: None,
+    };
+    record_revision(&mut prompt, None);
+
+    PROMPTS.with(|p| {
+        let mut prompts = p.borrow_mut();
+        prompts.insert(prompt_id, prompt.clone());
+    });
+    index_prompt(prompt_id);
+
+    // Update user stats
+    USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        if let Some(user) = users.get_mut(&caller) {
+            user.prompts_created += 1;
+        }
+    });
+
+    Ok(prompt)
+}
+
+#[ic_cdk::query]
+fn get_prompt(prompt_id: PromptId) -> Result<PromptSummary, PromptVaultError> {
+    let caller = get_caller();
+    PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        prompts
+            .get(&prompt_id)
+            .map(|prompt| summary_for(prompt, caller))
+            .ok_or_else(|| PromptVaultError::not_found("Prompt"))
+    })
+}
+
+#[ic_cdk::query]
+fn get_public_prompts(sort: Option<SortOptions>) -> Result<Vec<PromptSummary>, PromptVaultError> {
+    PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        let mut public_prompts: Vec<&Prompt> = prompts
+            .values()
+            .filter(|prompt| is_listed(prompt))
+            .collect();
+        sort_prompts(&mut public_prompts, sort.as_ref());
+
+        Ok(public_prompts
+            .into_iter()
+            .map(PromptSummary::from)
+            .collect())
+    })
+}
+
+#[ic_cdk::query]
+fn get_user_prompts(user_id: UserId) -> Result<Vec<PromptSummary>, PromptVaultError> {
+    let caller = get_caller();
+    PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        let user_prompts: Vec<PromptSummary> = prompts
+            .values()
+            .filter(|prompt| is_shown_on_profile(prompt, user_id, caller))
+            .map(PromptSummary::from)
+            .collect();
+
+        Ok(user_prompts)
+    })
+}
+
+#[ic_cdk::query]
+fn get_public_prompts_page(
+    page: PageRequest,
+    sort: Option<SortOptions>,
+) -> Result<Page<PromptSummary>, PromptVaultError> {
+    PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        let mut public_prompts: Vec<&Prompt> = prompts
+            .values()
+            .filter(|prompt| is_listed(prompt))
+            .collect();
+        sort_prompts(&mut public_prompts, sort.as_ref());
+
+        Ok(paginate(public_prompts, &page, PromptSummary::from))
+    })
+}
+
+#[ic_cdk::query]
+fn get_user_prompts_page(
+    user_id: UserId,
+    page: PageRequest,
+) -> Result<Page<PromptSummary>, PromptVaultError> {
+    let caller = get_caller();
+    PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        let mut user_prompts: Vec<&Prompt> = prompts
+            .values()
+            .filter(|prompt| is_shown_on_profile(prompt, user_id, caller))
+            .collect();
+        sort_prompts(&mut user_prompts, None);
+
+        Ok(paginate(user_prompts, &page, PromptSummary::from))
+    })
+}
+
+#[ic_cdk::update]
+fn update_prompt(request: UpdatePromptRequest) -> Result<Prompt, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    if !is_authorized(request.id, caller) {
+        return Err(PromptVaultError::Unauthorized);
+    }
+
+    if is_deleted(request.id) {
+        return Err(PromptVaultError::conflict("Prompt has been deleted"));
+    }
+
+    // New messages replace the content with their flattened form; new plain content or an
+    // empty message list drops the chain
+    let (content, messages) = match request.messages {
+        Some(messages) if !messages.is_empty() => {
+            validate_messages(&messages)?;
+            (Some(flatten_messages(&messages)), Some(messages))
+        }
+        Some(_) => (request.content, Some(Vec::new())),
+        None if request.content.is_some() => (request.content, Some(Vec::new())),
+        None => (None, None),
+    };
+
+    // Validate the pricing and template the prompt will end up with before touching it
+    let checks = PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        prompts.get(&request.id).map(|prompt| {
+            validate_pricing(
+                request.is_premium.unwrap_or(prompt.is_premium),
+                request.price.unwrap_or(prompt.price),
+            )?;
+            validate_variables(
+                content.as_deref().unwrap_or(&prompt.content),
+                request.variables.as_deref().unwrap_or(&prompt.variables),
+            )
+        })
+    });
+
+    if let Some(Err(error)) = checks {
+        return Err(error);
+    }
+
+    // Validate every field before touching the prompt so updates apply all or nothing
+    if let Some(ref title) = request.title {
+        if title.trim().is_empty() || title.len() > MAX_TITLE_LENGTH {
+            return Err(PromptVaultError::validation("title", "Invalid title"));
+        }
+    }
+    if let Some(ref description) = request.description {
+        if description.len() > MAX_DESCRIPTION_LENGTH {
+            return Err(PromptVaultError::validation(
+                "description",
+                "Description too long",
+            ));
+        }
+    }
+    if let Some(ref content) = content {
+        if content.trim().is_empty() || content.len() > MAX_CONTENT_LENGTH {
+            return Err(PromptVaultError::validation("content", "Invalid content"));
+        }
+    }
+    let tags = match request.tags {
+        Some(ref tags) => Some(
+            normalize_tags(tags).map_err(|reason| PromptVaultError::validation("tags", reason))?,
+        ),
+        None => None,
+    };
+    validate_changelog(request.changelog.as_deref())?;
+
+    let prompt = PROMPTS.with(|p| -> Result<Prompt, PromptVaultError> {
+        let mut prompts = p.borrow_mut();
+        let prompt = prompts
+            .get_mut(&request.id)
+            .ok_or_else(|| PromptVaultError::not_found("Prompt"))?;
+        let previous = prompt.clone();
+
+        if let Some(title) = request.title {
+            prompt.title = title.trim().to_string();
+        }
+        if let Some(description) = request.description {
+            prompt.description = description;
+        }
+        if let Some(content) = content {
+            prompt.content = content;
+        }
+        if let Some(messages) = messages {
+            prompt.messages = messages;
+        }
+        if let Some(variables) = request.variables {
+            prompt.variables = variables;
+        }
+        if let Some(category) = request.category {
+            prompt.category = category;
+        }
+        if let Some(tags) = tags {
+            prompt.tags = tags;
+        }
+        if let Some(price) = request.price {
+            prompt.price = price;
+        }
+        if let Some(is_premium) = request.is_premium {
+            prompt.is_premium = is_premium;
+        }
+        if let Some(is_public) = request.is_public {
+            prompt.is_public = is_public;
+        }
+        prompt.updated_at = get_time();
+
+        if has_versioned_changes(&previous, prompt) {
+            record_revision(prompt, request.changelog);
+        }
+        Ok(prompt.clone())
+    })?;
+
+    index_prompt(request.id);
+    Ok(prompt)
+}
+
+// Revision history of a prompt, oldest first; contents are fetched with get_prompt_revision
+#[ic_cdk::query]
+fn list_prompt_revisions(prompt_id: PromptId) -> Result<Vec<RevisionSummary>, PromptVaultError> {
+    let exists = PROMPTS.with(|p| p.borrow().contains_key(&prompt_id));
+    if !exists {
+        return Err(PromptVaultError::not_found("Prompt"));
+    }
+
+    PROMPT_REVISIONS.with(|pr| {
+        let revisions = pr.borrow();
+        Ok(revisions
+            .get(&prompt_id)
+            .map(|revisions| revisions.iter().map(RevisionSummary::from).collect())
+            .unwrap_or_default())
+    })
+}
+
+// Same access rules as get_prompt_content, so buyers can read every revision
+#[ic_cdk::query]
+fn get_prompt_revision(
+    prompt_id: PromptId,
+    revision: u32,
+) -> Result<PromptRevision, PromptVaultError> {
+    let caller = get_caller();
+
+    let can_access = PROMPTS
+        .with(|p| {
+            let prompts = p.borrow();
+            prompts
+                .get(&prompt_id)
+                .map(|prompt| can_access_content(prompt, caller))
+        })
+        .ok_or_else(|| PromptVaultError::not_found("Prompt"))?;
+
+    if !can_access {
+        return Err(PromptVaultError::forbidden(
+            "Access denied. Purchase required.",
+        ));
+    }
+
+    find_revision(prompt_id, revision).ok_or_else(|| PromptVaultError::not_found("Revision"))
+}
+
+// Makes an old revision current again by appending a copy of it, so history is never rewritten
+#[ic_cdk::update]
+fn restore_prompt_revision(
+    prompt_id: PromptId,
+    revision: u32,
+    changelog: Option<String>,
+) -> Result<Prompt, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    if !is_authorized(prompt_id, caller) {
+        return Err(PromptVaultError::Unauthorized);
+    }
+
+    if is_deleted(prompt_id) {
+        return Err(PromptVaultError::conflict("Prompt has been deleted"));
+    }
+
+    validate_changelog(changelog.as_deref())?;
+
+    let restored = find_revision(prompt_id, revision)
+        .ok_or_else(|| PromptVaultError::not_found("Revision"))?;
+
+    let prompt = PROMPTS.with(|p| -> Result<Prompt, PromptVaultError> {
+        let mut prompts = p.borrow_mut();
+        let prompt = prompts
+            .get_mut(&prompt_id)
+            .ok_or_else(|| PromptVaultError::not_found("Prompt"))?;
+
+        prompt.title = restored.title;
+        prompt.description = restored.description;
+        prompt.content = restored.content;
+        prompt.variables = restored.variables;
+        prompt.messages = restored.messages;
+        prompt.updated_at = get_time();
+        record_revision(
+            prompt,
+            Some(changelog.unwrap_or_else(|| format!("Restored revision {}", revision))),
+        );
+        Ok(prompt.clone())
+    })?;
+
+    index_prompt(prompt_id);
+    Ok(prompt)
+}
+
+// Prompts with buyers are only delisted so past buyers keep access to the content
+#[ic_cdk::update]
+fn delete_prompt(prompt_id: PromptId) -> Result<String, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    if !is_authorized(prompt_id, caller) {
+        return Err(PromptVaultError::Unauthorized);
+    }
+
+    let has_buyers = PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        prompts.get(&prompt_id).map(|prompt| {
+            if prompt.deleted_at.is_some() {
+                Err(PromptVaultError::conflict(
+                    "Prompt has already been deleted",
+                ))
+            } else {
+                // A purchase awaiting the ledger will add a buyer once it completes
+                Ok(prompt.purchases > 0 || has_pending_purchase(prompt_id))
+            }
+        })
+    });
+
+    let has_buyers = has_buyers.ok_or_else(|| PromptVaultError::not_found("Prompt"))??;
+
+    if has_buyers {
+        PROMPTS.with(|p| {
+            let mut prompts = p.borrow_mut();
+            if let Some(prompt) = prompts.get_mut(&prompt_id) {
+                prompt.deleted_at = Some(get_time());
+            }
+        });
+        decrement_prompts_created(caller);
+
+        Ok("Prompt delisted; existing buyers keep access".to_string())
+    } else {
+        remove_prompt(prompt_id)?;
+
+        Ok("Prompt deleted successfully".to_string())
+    }
+}
+
+// Hard-deletes a prompt even if it has buyers, e.g. for takedowns
+#[ic_cdk::update]
+fn admin_delete_prompt(prompt_id: PromptId) -> Result<String, PromptVaultError> {
+    require_role(Role::Admin)?;
+
+    remove_prompt(prompt_id)?;
+    Ok("Prompt deleted successfully".to_string())
+}
+
+#[ic_cdk::update]
+async fn purchase_prompt(
+    prompt_id: PromptId,
+    method: Option<PaymentMethod>,
+) -> Result<Purchase, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    // Check if prompt exists
+    let prompt = PROMPTS
+        .with(|p| {
+            let prompts = p.borrow();
+            prompts
+                .get(&prompt_id)
+                .filter(|prompt| {
+                    prompt.deleted_at.is_none()
+                        && prompt.hidden_at.is_none()
+                        && !is_banned(prompt.author)
+                })
+                .cloned()
+        })
+        .ok_or_else(|| PromptVaultError::not_found("Prompt"))?;
+
+    // Check if user is trying to buy their own prompt
+    if prompt.author == caller {
+        return Err(PromptVaultError::forbidden(
+            "Cannot purchase your own prompt",
+        ));
+    }
+
+    // Check if already purchased
+    if has_purchased(caller, prompt_id) {
+        return Err(PromptVaultError::AlreadyPurchased);
+    }
+
+    // A payment that can't go through after settling is refunded to the buyer's balance
+    if prompt.price > 0 && !USERS.with(|u| u.borrow().contains_key(&caller)) {
+        return Err(PromptVaultError::not_found("User"));
+    }
+
+    // Reject concurrent attempts for the same prompt while the ledger call is pending
+    let _guard = PurchaseGuard::new(caller, prompt_id)?;
+
+    // Collect the price into escrow; the seller withdraws their share from their balance
+    let (block_index, ledger_fee) = if prompt.price > 0 {
+        match method.unwrap_or(PaymentMethod::Deposit) {
+            PaymentMethod::Deposit => {
+                let (block_index, fee) = settle_from_deposit(caller, &prompt).await?;
+                (Some(block_index), fee)
+            }
+            PaymentMethod::Approval => {
+                let (block_index, fee) = settle_from_approval(caller, &prompt).await?;
+                (Some(block_index), fee)
+            }
+            PaymentMethod::Balance => {
+                let _balance_guard = BalanceGuard::new(caller)?;
+                debit_balance(caller, prompt.price)?;
+                (None, 0)
+            }
+        }
+    } else {
+        (None, 0)
+    };
+
+    // The author may have been banned while the ledger call was in flight; the price is
+    // already in escrow, so it goes back to the buyer instead of to the seller
+    if is_banned(prompt.author) {
+        USERS.with(|u| {
+            if let Some(buyer) = u.borrow_mut().get_mut(&caller) {
+                buyer.balance += prompt.price;
+            }
+        });
+        FEE_TOTALS.with(|f| f.borrow_mut().ledger_fees_paid += ledger_fee);
+        return Err(PromptVaultError::conflict(
+            "The seller was banned before the purchase completed; the price was refunded to your balance",
+        ));
+    }
+
+    let fee_amount = platform_fee(prompt.price);
+    let seller_amount = prompt.price - fee_amount;
+
+    let purchase = Purchase {
+        prompt_id,
+        buyer: caller,
+        seller: prompt.author,
+        price: prompt.price,
+        timestamp: get_time(),
+        block_index,
+        seller_amount,
+        fee_amount,
+        ledger_fee,
+    };
+
+    // Record purchase
+    PURCHASES.with(|p| {
+        let mut purchases = p.borrow_mut();
+        purchases.push(purchase.clone());
+    });
+
+    // Update user purchases
+    USER_PURCHASES.with(|up| {
+        let mut user_purchases = up.borrow_mut();
+        user_purchases
+            .entry(caller)
+            .or_insert_with(Vec::new)
+            .push(prompt_id);
+    });
+
+    // Update prompt stats
+    PROMPTS.with(|p| {
+        let mut prompts = p.borrow_mut();
+        if let Some(prompt) = prompts.get_mut(&prompt_id) {
+            prompt.purchases += 1;
+        }
+    });
+
+    // Update user stats
+    USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        if let Some(buyer) = users.get_mut(&caller) {
+            buyer.prompts_purchased += 1;
+            buyer.total_spent += prompt.price;
+        }
+        if let Some(seller) = users.get_mut(&prompt.author) {
+            seller.total_earnings += seller_amount;
+            seller.balance += seller_amount;
+        }
+    });
+
+    FEE_TOTALS.with(|f| {
+        let mut totals = f.borrow_mut();
+        totals.sales_volume += prompt.price;
+        totals.fees_collected += fee_amount;
+        totals.ledger_fees_paid += ledger_fee;
+    });
+
+    Ok(purchase)
+}
+
+// Move the price from the buyer's deposit subaccount into escrow; returns (block, ledger fee)
+async fn settle_from_deposit(
+    buyer: UserId,
+    prompt: &Prompt,
+) -> Result<(u64, u64), PromptVaultError> {
+    let fee = ledger_fee().await?;
+    let available = ledger_balance_of(deposit_account(buyer)).await?;
+    let required = prompt.price.saturating_add(fee);
+    if available < required {
+        return Err(PromptVaultError::InsufficientFunds {
+            required,
+            available,
+        });
+    }
+
+    let block_index = ledger_transfer(TransferArg {
+        from_subaccount: Some(deposit_subaccount(buyer)),
+        to: escrow_account(),
+        amount: Nat::from(prompt.price),
+        fee: Some(Nat::from(fee)),
+        memo: Some(prompt.id.to_be_bytes().to_vec()),
+        created_at_time: Some(get_time()),
+    })
+    .await?;
+    Ok((block_index, fee))
+}
+
+// Pull the price from the buyer's own account under their ICRC-2 allowance
+async fn settle_from_approval(
+    buyer: UserId,
+    prompt: &Prompt,
+) -> Result<(u64, u64), PromptVaultError> {
+    let fee = ledger_fee().await?;
+    let block_index = ledger_transfer_from(TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: buyer,
+            subaccount: None,
+        },
+        to: escrow_account(),
+        amount: Nat::from(prompt.price),
+        fee: Some(Nat::from(fee)),
+        memo: Some(prompt.id.to_be_bytes().to_vec()),
+        created_at_time: Some(get_time()),
+    })
+    .await?;
+    Ok((block_index, fee))
+}
+
+fn debit_balance(user_id: UserId, amount: u64) -> Result<(), PromptVaultError> {
+    USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        match users.get_mut(&user_id) {
+            Some(user) if user.balance >= amount => {
+                user.balance -= amount;
+                Ok(())
+            }
+            Some(user) => Err(PromptVaultError::InsufficientFunds {
+                required: amount,
+                available: user.balance,
+            }),
+            None => Err(PromptVaultError::not_found("User")),
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn get_prompt_content(prompt_id: PromptId) -> Result<PromptContent, PromptVaultError> {
+    let caller = get_caller();
+
+    let prompt = PROMPTS
+        .with(|p| {
+            let prompts = p.borrow();
+            prompts.get(&prompt_id).cloned()
+        })
+        .ok_or_else(|| PromptVaultError::not_found("Prompt"))?;
+
+    // Allow access if: user is author, user has purchased, or prompt is listed and free
+    if can_access_content(&prompt, caller) {
+        Ok(PromptContent {
+            messages: prompt.messages,
+            flattened: prompt.content,
+        })
+    } else {
+        Err(PromptVaultError::forbidden(
+            "Access denied. Purchase required.",
+        ))
+    }
+}
+
+// Fills the prompt's placeholders, in every message of a chain too; values fall back to
+// the declared defaults
+#[ic_cdk::query]
+fn render_prompt(
+    prompt_id: PromptId,
+    values: HashMap<String, String>,
+) -> Result<PromptContent, PromptVaultError> {
+    let caller = get_caller();
+
+    let prompt = PROMPTS
+        .with(|p| {
+            let prompts = p.borrow();
+            prompts.get(&prompt_id).cloned()
+        })
+        .ok_or_else(|| PromptVaultError::not_found("Prompt"))?;
+
+    if !can_access_content(&prompt, caller) {
+        return Err(PromptVaultError::forbidden(
+            "Access denied. Purchase required.",
+        ));
+    }
+
+    if let Some(unknown) = values
+        .keys()
+        .find(|name| !prompt.variables.iter().any(|v| &v.name == *name))
+    {
+        return Err(PromptVaultError::validation(
+            unknown,
+            format!("Unknown variable {}", unknown),
+        ));
+    }
+
+    // Without declared variables, braces in the content are plain text
+    if prompt.variables.is_empty() {
+        return Ok(PromptContent {
+            messages: prompt.messages,
+            flattened: prompt.content,
+        });
+    }
+
+    let mut resolved: HashMap<&str, &str> = HashMap::new();
+    for variable in &prompt.variables {
+        let value = values
+            .get(&variable.name)
+            .or(variable.default_value.as_ref());
+        match value {
+            Some(value) => {
+                validate_variable_value(variable, value)?;
+                resolved.insert(&variable.name, value);
+            }
+            None if variable.required => {
+                return Err(PromptVaultError::validation(
+                    &variable.name,
+                    format!("Missing value for required variable {}", variable.name),
+                ));
+            }
+            None => {
+                resolved.insert(&variable.name, "");
+            }
+        }
+    }
+
+    Ok(PromptContent {
+        messages: prompt
+            .messages
+            .iter()
+            .map(|message| PromptMessage {
+                role: message.role.clone(),
+                text: fill_template(&message.text, &resolved),
+            })
+            .collect(),
+        flattened: fill_template(&prompt.content, &resolved),
+    })
+}
+
+fn fill_template(text: &str, values: &HashMap<&str, &str>) -> String {
+    template_segments(text)
+        .into_iter()
+        .map(|segment| match segment {
+            TemplateSegment::Text(text) => text,
+            TemplateSegment::Placeholder(name) => values.get(name).copied().unwrap_or_default(),
+        })
+        .collect()
+}
+
+#[ic_cdk::update]
+fn like_prompt(prompt_id: PromptId) -> Result<String, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    // Check if prompt exists
+    let prompt_exists = PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        prompts
+            .get(&prompt_id)
+            .is_some_and(|prompt| prompt.deleted_at.is_none())
+    });
+
+    if !prompt_exists {
+        return Err(PromptVaultError::not_found("Prompt"));
+    }
+
+    // Check if already liked
+    let already_liked = USER_LIKES.with(|ul| {
+        let user_likes = ul.borrow();
+        match user_likes.get(&caller) {
+            Some(likes) => likes.contains(&prompt_id),
+            None => false,
+        }
+    });
+
+    if already_liked {
+        return Err(PromptVaultError::conflict("Prompt already liked"));
+    }
+
+    // Add like
+    USER_LIKES.with(|ul| {
+        let mut user_likes = ul.borrow_mut();
+        user_likes
+            .entry(caller)
+            .or_insert_with(Vec::new)
+            .push(prompt_id);
+    });
+
+    // Update prompt likes count
+    PROMPTS.with(|p| {
+        let mut prompts = p.borrow_mut();
+        if let Some(prompt) = prompts.get_mut(&prompt_id) {
+            prompt.likes += 1;
+        }
+    });
+
+    Ok("Prompt liked successfully".to_string())
+}
+
+#[ic_cdk::update]
+fn unlike_prompt(prompt_id: PromptId) -> Result<String, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    // Check if prompt exists
+    let prompt_exists = PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        prompts.contains_key(&prompt_id)
+    });
+
+    if !prompt_exists {
+        return Err(PromptVaultError::not_found("Prompt"));
+    }
+
+    // Check if actually liked
+    let was_liked = USER_LIKES.with(|ul| {
+        let mut user_likes = ul.borrow_mut();
+        match user_likes.get_mut(&caller) {
+            Some(likes) => {
+                if let Some(pos) = likes.iter().position(|&x| x == prompt_id) {
+                    likes.remove(pos);
+                    true
+                } else {
+                    false
+                }
+            }
+            None => false,
+        }
+    });
+
+    if !was_liked {
+        return Err(PromptVaultError::conflict("Prompt was not liked"));
+    }
+
+    // Update prompt likes count
+    PROMPTS.with(|p| {
+        let mut prompts = p.borrow_mut();
+        if let Some(prompt) = prompts.get_mut(&prompt_id) {
+            if prompt.likes > 0 {
+                prompt.likes -= 1;
+            }
+        }
+    });
+
+    Ok("Prompt unliked successfully".to_string())
+}
+
+#[ic_cdk::update]
+fn rate_prompt(request: RatePromptRequest) -> Result<String, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    // Validate rating
+    validate_rating(request.rating)?;
+    let review_text = request
+        .review
+        .as_deref()
+        .map(|text| validate_review_text("review", text, MAX_REVIEW_LENGTH))
+        .transpose()?;
+
+    // Check if prompt exists
+    let prompt_exists = PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        prompts.contains_key(&request.prompt_id)
+    });
+
+    if !prompt_exists {
+        return Err(PromptVaultError::not_found("Prompt"));
+    }
+
+    // Check if user owns the prompt
+    if is_authorized(request.prompt_id, caller) {
+        return Err(PromptVaultError::forbidden("Cannot rate your own prompt"));
+    }
+
+    // Check if user has purchased the prompt or it's public
+    let can_rate = PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        if let Some(prompt) = prompts.get(&request.prompt_id) {
+            can_access_content(prompt, caller)
+        } else {
+            false
+        }
+    });
+
+    if !can_rate {
+        return Err(PromptVaultError::forbidden(
+            "Must purchase prompt to rate it",
+        ));
+    }
+
+    if !USERS.with(|u| u.borrow().contains_key(&caller)) {
+        return Err(PromptVaultError::not_found("User"));
+    }
+    let now = get_time();
+    let verified_purchase = has_purchased(caller, request.prompt_id);
+    check_rating_eligibility(caller, verified_purchase, now)?;
+
+    // Record or update the rating; a previous rating the policy didn't count isn't
+    // in the aggregates to take out
+    let previous_rating = counted_rating(caller, request.prompt_id);
+    USER_RATINGS.with(|ur| {
+        let mut user_ratings = ur.borrow_mut();
+        user_ratings
+            .entry(caller)
+            .or_insert_with(HashMap::new)
+            .insert(request.prompt_id, request.rating);
+    });
+
+    // Update prompt rating
+    PROMPTS.with(|p| {
+        let mut prompts = p.borrow_mut();
+        if let Some(prompt) = prompts.get_mut(&request.prompt_id) {
+            update_rating_aggregates(prompt, previous_rating, Some(request.rating));
+        }
+    });
+
+    REVIEWS.with(|r| {
+        let mut reviews = r.borrow_mut();
+        let review = reviews
+            .entry(request.prompt_id)
+            .or_default()
+            .entry(caller)
+            .or_insert_with(|| Review {
+                prompt_id: request.prompt_id,
+                reviewer: caller,
+                rating: request.rating,
+                text: None,
+                verified_purchase,
+                created_at: now,
+                updated_at: now,
+                reply: None,
+                helpful_votes: 0,
+                unhelpful_votes: 0,
+            });
+        review.rating = request.rating;
+        review.verified_purchase = verified_purchase;
+        review.updated_at = now;
+        if let Some(text) = review_text {
+            review.text = text;
+        }
+    });
+
+    Ok("Prompt rated successfully".to_string())
+}
+
+// Takes back the caller's rating of a prompt, review included
+#[ic_cdk::update]
+fn unrate_prompt(prompt_id: PromptId) -> Result<String, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    let counted = counted_rating(caller, prompt_id);
+    USER_RATINGS
+        .with(|ur| {
+            let mut user_ratings = ur.borrow_mut();
+            let ratings = user_ratings.get_mut(&caller)?;
+            let previous = ratings.remove(&prompt_id);
+            if ratings.is_empty() {
+                user_ratings.remove(&caller);
+            }
+            previous
+        })
+        .ok_or_else(|| PromptVaultError::not_found("Rating"))?;
+
+    PROMPTS.with(|p| {
+        let mut prompts = p.borrow_mut();
+        if let Some(prompt) = prompts.get_mut(&prompt_id) {
+            update_rating_aggregates(prompt, counted, None);
+        }
+    });
+    REVIEWS.with(|r| {
+        if let Some(reviews) = r.borrow_mut().get_mut(&prompt_id) {
+            reviews.remove(&caller);
+        }
+    });
+    REVIEW_VOTES.with(|rv| {
+        rv.borrow_mut().remove(&(prompt_id, caller));
+    });
+
+    Ok("Rating removed successfully".to_string())
+}
+
+// The caller's ratings, most recently rated first
+#[ic_cdk::query]
+fn get_my_ratings(page: PageRequest) -> Result<Page<UserRating>, PromptVaultError> {
+    let caller = get_caller();
+
+    let ratings: Vec<(PromptId, u8)> = USER_RATINGS.with(|ur| {
+        ur.borrow()
+            .get(&caller)
+            .map(|ratings| ratings.iter().map(|(id, rating)| (*id, *rating)).collect())
+            .unwrap_or_default()
+    });
+    let mut ratings: Vec<UserRating> = REVIEWS.with(|r| {
+        let reviews = r.borrow();
+        ratings
+            .into_iter()
+            .map(|(prompt_id, rating)| UserRating {
+                prompt_id,
+                rating,
+                review: reviews
+                    .get(&prompt_id)
+                    .and_then(|reviews| reviews.get(&caller))
+                    .cloned(),
+            })
+            .collect()
+    });
+
+    let rated_at = |rating: &UserRating| rating.review.as_ref().map_or(0, |r| r.updated_at);
+    ratings.sort_by(|a, b| {
+        rated_at(b)
+            .cmp(&rated_at(a))
+            .then(b.prompt_id.cmp(&a.prompt_id))
+    });
+    Ok(paginate(ratings, &page, |rating| rating))
+}
+
+#[ic_cdk::query]
+fn get_rating_distribution(prompt_id: PromptId) -> Result<RatingDistribution, PromptVaultError> {
+    check_reviews_visible(prompt_id, get_caller())?;
+
+    let prior = rating_prior();
+    PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        let prompt = prompts
+            .get(&prompt_id)
+            .ok_or_else(|| PromptVaultError::not_found("Prompt"))?;
+        Ok(RatingDistribution {
+            counts: prompt.rating_counts,
+            total: prompt.total_ratings,
+            average: prompt.rating,
+            weighted_rating: weighted_rating(prompt, prior),
+        })
+    })
+}
+
+// Most recently updated first
+#[ic_cdk::query]
+fn get_prompt_reviews(
+    prompt_id: PromptId,
+    page: PageRequest,
+) -> Result<Page<Review>, PromptVaultError> {
+    check_reviews_visible(prompt_id, get_caller())?;
+
+    let mut reviews: Vec<Review> = REVIEWS.with(|r| {
+        r.borrow()
+            .get(&prompt_id)
+            .map(|reviews| reviews.values().cloned().collect())
+            .unwrap_or_default()
+    });
+    reviews.sort_by(|a, b| {
+        b.updated_at
+            .cmp(&a.updated_at)
+            .then_with(|| a.reviewer.cmp(&b.reviewer))
+    });
+    Ok(paginate(reviews, &page, |review| review))
+}
+
+// Adds or replaces the author's reply to one review
+#[ic_cdk::update]
+fn reply_to_review(
+    prompt_id: PromptId,
+    reviewer: UserId,
+    text: String,
+) -> Result<Review, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    let author = PROMPTS
+        .with(|p| p.borrow().get(&prompt_id).map(|prompt| prompt.author))
+        .ok_or_else(|| PromptVaultError::not_found("Prompt"))?;
+    if author != caller {
+        return Err(PromptVaultError::Unauthorized);
+    }
+
+    let text = validate_review_text("text", &text, MAX_REVIEW_REPLY_LENGTH)?
+        .ok_or_else(|| PromptVaultError::validation("text", "Reply cannot be empty"))?;
+
+    REVIEWS.with(|r| {
+        let mut reviews = r.borrow_mut();
+        let review = reviews
+            .get_mut(&prompt_id)
+            .and_then(|reviews| reviews.get_mut(&reviewer))
+            .ok_or_else(|| PromptVaultError::not_found("Review"))?;
+        let now = get_time();
+        let created_at = review.reply.as_ref().map_or(now, |reply| reply.created_at);
+        review.reply = Some(ReviewReply {
+            text,
+            created_at,
+            updated_at: now,
+        });
+        Ok(review.clone())
+    })
+}
+
+// One vote per user and review; voting again replaces the earlier vote
+#[ic_cdk::update]
+fn vote_review(
+    prompt_id: PromptId,
+    reviewer: UserId,
+    helpful: bool,
+) -> Result<Review, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    if caller == Principal::anonymous() {
+        return Err(PromptVaultError::Unauthorized);
+    }
+    // Only registered users vote, so throwaway principals can't pile up votes
+    let user_exists = USERS.with(|u| u.borrow().contains_key(&caller));
+    if !user_exists {
+        return Err(PromptVaultError::not_found("User"));
+    }
+    if caller == reviewer {
+        return Err(PromptVaultError::forbidden(
+            "Cannot vote on your own review",
+        ));
+    }
+    check_reviews_visible(prompt_id, caller)?;
+
+    let exists = REVIEWS.with(|r| {
+        r.borrow()
+            .get(&prompt_id)
+            .is_some_and(|reviews| reviews.contains_key(&reviewer))
+    });
+    if !exists {
+        return Err(PromptVaultError::not_found("Review"));
+    }
+
+    let previous = REVIEW_VOTES.with(|rv| {
+        rv.borrow_mut()
+            .entry((prompt_id, reviewer))
+            .or_default()
+            .insert(caller, helpful)
+    });
+
+    REVIEWS.with(|r| {
+        let mut reviews = r.borrow_mut();
+        let review = reviews
+            .get_mut(&prompt_id)
+            .and_then(|reviews| reviews.get_mut(&reviewer))
+            .ok_or_else(|| PromptVaultError::not_found("Review"))?;
+        if previous != Some(helpful) {
+            match previous {
+                Some(true) => review.helpful_votes = review.helpful_votes.saturating_sub(1),
+                Some(false) => review.unhelpful_votes = review.unhelpful_votes.saturating_sub(1),
+                None => {}
+            }
+            if helpful {
+                review.helpful_votes += 1;
+            } else {
+                review.unhelpful_votes += 1;
+            }
+        }
+        Ok(review.clone())
+    })
+}
+
+// Posts a top-level comment, or a reply when `parent_id` is set
+#[ic_cdk::update]
+fn add_comment(
+    prompt_id: PromptId,
+    parent_id: Option<CommentId>,
+    text: String,
+) -> Result<Comment, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    let text = validate_comment(&text)?;
+    require_comments_open(prompt_id)?;
+
+    let user_exists = USERS.with(|u| u.borrow().contains_key(&caller));
+    if !user_exists {
+        return Err(PromptVaultError::not_found("User"));
+    }
+
+    if let Some(parent_id) = parent_id {
+        let parent = find_comment(parent_id)?;
+        if parent.prompt_id != prompt_id {
+            return Err(PromptVaultError::not_found("Comment"));
+        }
+    }
+
+    let comment_id = NEXT_COMMENT_ID.with(|id| {
+        let mut next_id = id.borrow_mut();
+        let current_id = *next_id;
+        *next_id += 1;
+        current_id
+    });
+
+    let comment = Comment {
+        id: comment_id,
+        prompt_id,
+        author: caller,
+        parent_id,
+        text,
+        created_at: get_time(),
+        edited_at: None,
+        deleted_at: None,
+        removed_by_moderator: false,
+        reply_count: 0,
+    };
+
+    COMMENTS.with(|c| {
+        let mut comments = c.borrow_mut();
+        if let Some(parent) = parent_id.and_then(|parent_id| comments.get_mut(&parent_id)) {
+            parent.reply_count += 1;
+        }
+        comments.insert(comment_id, comment.clone());
+    });
+
+    Ok(comment)
+}
+
+#[ic_cdk::update]
+fn edit_comment(comment_id: CommentId, text: String) -> Result<Comment, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    let text = validate_comment(&text)?;
+    let comment = find_comment(comment_id)?;
+    if comment.author != caller {
+        return Err(PromptVaultError::Unauthorized);
+    }
+    require_comments_open(comment.prompt_id)?;
+
+    COMMENTS.with(|c| {
+        let mut comments = c.borrow_mut();
+        let comment = comments
+            .get_mut(&comment_id)
+            .ok_or_else(|| PromptVaultError::not_found("Comment"))?;
+        comment.text = text;
+        comment.edited_at = Some(get_time());
+        Ok(comment.clone())
+    })
+}
+
+#[ic_cdk::update]
+fn delete_comment(comment_id: CommentId) -> Result<String, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    let comment = find_comment(comment_id)?;
+    if comment.author != caller {
+        return Err(PromptVaultError::Unauthorized);
+    }
+
+    discard_comment(comment_id, false);
+    Ok("Comment deleted successfully".to_string())
+}
+
+#[ic_cdk::update]
+fn remove_comment(comment_id: CommentId) -> Result<String, PromptVaultError> {
+    require_role(Role::Moderator)?;
+
+    find_comment(comment_id)?;
+    discard_comment(comment_id, true);
+    Ok("Comment removed successfully".to_string())
+}
+
+// Replies to `parent_id`, or the top-level comments when None, oldest first.
+// Moderators can read the comments of prompts that are no longer listed.
+#[ic_cdk::query]
+fn get_prompt_comments(
+    prompt_id: PromptId,
+    parent_id: Option<CommentId>,
+    page: PageRequest,
+) -> Result<Page<Comment>, PromptVaultError> {
+    if !has_role(get_caller(), &Role::Moderator) {
+        require_comments_open(prompt_id)?;
+    }
+
+    let comments: Vec<Comment> = COMMENTS.with(|c| {
+        c.borrow()
+            .values()
+            .filter(|comment| comment.prompt_id == prompt_id && comment.parent_id == parent_id)
+            .cloned()
+            .collect()
+    });
+    Ok(paginate(comments, &page, |comment| comment))
+}
+
+#[ic_cdk::update]
+fn follow_user(user_id: UserId) -> Result<String, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    if user_id == caller {
+        return Err(PromptVaultError::forbidden("Cannot follow yourself"));
+    }
+
+    let (caller_exists, user_exists) = USERS.with(|u| {
+        let users = u.borrow();
+        (users.contains_key(&caller), users.contains_key(&user_id))
+    });
+    if !caller_exists || !user_exists {
+        return Err(PromptVaultError::not_found("User"));
+    }
+
+    let newly_followed =
+        USER_FOLLOWING.with(|uf| uf.borrow_mut().entry(caller).or_default().insert(user_id));
+    if !newly_followed {
+        return Err(PromptVaultError::conflict("User already followed"));
+    }
+    USER_FOLLOWERS.with(|uf| {
+        uf.borrow_mut().entry(user_id).or_default().insert(caller);
+    });
+
+    USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        if let Some(user) = users.get_mut(&caller) {
+            user.following_count += 1;
+        }
+        if let Some(user) = users.get_mut(&user_id) {
+            user.follower_count += 1;
+        }
+    });
+
+    Ok("User followed successfully".to_string())
+}
+
+#[ic_cdk::update]
+fn unfollow_user(user_id: UserId) -> Result<String, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    let was_following = USER_FOLLOWING.with(|uf| {
+        let mut following = uf.borrow_mut();
+        let Some(followed) = following.get_mut(&caller) else {
+            return false;
+        };
+        let removed = followed.remove(&user_id);
+        if followed.is_empty() {
+            following.remove(&caller);
+        }
+        removed
+    });
+    if !was_following {
+        return Err(PromptVaultError::conflict("User was not followed"));
+    }
+    USER_FOLLOWERS.with(|uf| {
+        let mut followers = uf.borrow_mut();
+        if let Some(users) = followers.get_mut(&user_id) {
+            users.remove(&caller);
+            if users.is_empty() {
+                followers.remove(&user_id);
+            }
+        }
+    });
+
+    USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        if let Some(user) = users.get_mut(&caller) {
+            user.following_count = user.following_count.saturating_sub(1);
+        }
+        if let Some(user) = users.get_mut(&user_id) {
+            user.follower_count = user.follower_count.saturating_sub(1);
+        }
+    });
+
+    Ok("User unfollowed successfully".to_string())
+}
+
+#[ic_cdk::query]
+fn get_followers(user_id: UserId, page: PageRequest) -> Result<Page<UserId>, PromptVaultError> {
+    get_user(user_id)?;
+    let followers: Vec<UserId> = USER_FOLLOWERS.with(|uf| {
+        uf.borrow()
+            .get(&user_id)
+            .map(|users| users.iter().copied().collect())
+            .unwrap_or_default()
+    });
+    Ok(paginate(followers, &page, |user_id| user_id))
+}
+
+#[ic_cdk::query]
+fn get_following(user_id: UserId, page: PageRequest) -> Result<Page<UserId>, PromptVaultError> {
+    get_user(user_id)?;
+    let following: Vec<UserId> = USER_FOLLOWING.with(|uf| {
+        uf.borrow()
+            .get(&user_id)
+            .map(|users| users.iter().copied().collect())
+            .unwrap_or_default()
+    });
+    Ok(paginate(following, &page, |user_id| user_id))
+}
+
+// Listed prompts of the users the caller follows, newest first
+#[ic_cdk::query]
+fn get_feed(page: PageRequest) -> Result<Page<PromptSummary>, PromptVaultError> {
+    let following =
+        USER_FOLLOWING.with(|uf| uf.borrow().get(&get_caller()).cloned().unwrap_or_default());
+
+    PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        let mut feed: Vec<&Prompt> = prompts
+            .values()
+            .filter(|prompt| following.contains(&prompt.author) && is_listed(prompt))
+            .collect();
+        feed.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(b.id.cmp(&a.id)));
+        Ok(paginate(feed, &page, PromptSummary::from))
+    })
+}
+
+#[ic_cdk::query]
+fn get_user_purchases(user_id: UserId) -> Result<Vec<PromptId>, PromptVaultError> {
+    USER_PURCHASES.with(|up| {
+        let user_purchases = up.borrow();
+        Ok(user_purchases.get(&user_id).cloned().unwrap_or_default())
+    })
+}
+
+#[query]
+fn search_prompts(
+    query: String,
+    category: Option<PromptCategory>,
+    sort: Option<SortOptions>,
+) -> Result<Vec<PromptSummary>, PromptVaultError> {
+    PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        let results = sort_search_results(
+            search_matches(&prompts, &legacy_search_query(query, category), true),
+            sort,
+        );
+
+        Ok(results.into_iter().map(PromptSummary::from).collect())
+    })
+}
+
+#[ic_cdk::query]
+fn search_prompts_page(
+    query: String,
+    category: Option<PromptCategory>,
+    page: PageRequest,
+    sort: Option<SortOptions>,
+) -> Result<Page<PromptSummary>, PromptVaultError> {
+    PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        let results = sort_search_results(
+            search_matches(&prompts, &legacy_search_query(query, category), true),
+            sort,
+        );
+
+        Ok(paginate(results, &page, PromptSummary::from))
+    })
+}
+
+// Full-text search with structured filters, ordered by BM25 score, best match first
+#[ic_cdk::query]
+fn search_prompts_ranked(
+    query: SearchQuery,
+    page: PageRequest,
+) -> Result<Page<SearchHit>, PromptVaultError> {
+    PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        let mut hits = search_matches(&prompts, &query, false);
+        hits.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.id.cmp(&b.id)));
+
+        Ok(paginate(hits, &page, |(prompt, score)| SearchHit {
+            prompt: PromptSummary::from(prompt),
+            score,
+        }))
+    })
+}
+
+// search_prompts semantics: every term must match, each as a prefix
+fn legacy_search_query(text: String, category: Option<PromptCategory>) -> SearchQuery {
+    SearchQuery {
+        text,
+        mode: Some(MatchMode::All),
+        categories: category.map(|category| vec![category]),
+        ..SearchQuery::default()
+    }
+}
+
+// Listed prompts passing the filters and the text query, with their BM25 scores
+fn search_matches<'a>(
+    prompts: &'a HashMap<PromptId, Prompt>,
+    query: &SearchQuery,
+    prefix_all: bool,
+) -> Vec<(&'a Prompt, f64)> {
+    let mode = query.mode.clone().unwrap_or(MatchMode::All);
+    match text_match_scores(&query.text, &mode, prefix_all) {
+        Some(scores) => scores
+            .into_iter()
+            .filter_map(|(prompt_id, score)| prompts.get(&prompt_id).map(|prompt| (prompt, score)))
+            .filter(|(prompt, _)| matches_search_filters(prompt, query))
+            .collect(),
+        None => prompts
+            .values()
+            .filter(|prompt| matches_search_filters(prompt, query))
+            .map(|prompt| (prompt, 0.0))
+            .collect(),
+    }
+}
+
+// Search results default to relevance, best match first
+fn search_sort(sort: Option<SortOptions>) -> SortOptions {
+    sort.unwrap_or(SortOptions {
+        sort_by: SortBy::Relevance,
+        direction: None,
+    })
+}
+
+// Relevance orders by BM25 score, then by popularity, which is all there is to go by
+// without a text query; other keys sort as in the listings
+fn sort_search_results(
+    mut results: Vec<(&Prompt, f64)>,
+    sort: Option<SortOptions>,
+) -> Vec<&Prompt> {
+    let sort = search_sort(sort);
+    if sort.sort_by != SortBy::Relevance {
+        let mut prompts: Vec<&Prompt> = results.into_iter().map(|(prompt, _)| prompt).collect();
+        sort_prompts(&mut prompts, Some(&sort));
+        return prompts;
+    }
+
+    results.sort_by(|(a, a_score), (b, b_score)| {
+        let ordering = a_score
+            .total_cmp(b_score)
+            .then_with(|| popularity_score(a).cmp(&popularity_score(b)));
+        let ordering = match sort.direction {
+            Some(SortDirection::Ascending) => ordering,
+            Some(SortDirection::Descending) | None => ordering.reverse(),
+        };
+        ordering.then(a.id.cmp(&b.id))
+    });
+    results.into_iter().map(|(prompt, _)| prompt).collect()
+}
+
+// Tags used by listed prompts, most used first; `prefix` narrows it down for autocomplete
+#[ic_cdk::query]
+fn list_tags(
+    prefix: Option<String>,
+    limit: Option<u32>,
+) -> Result<Vec<TagCount>, PromptVaultError> {
+    let prefix = match prefix {
+        Some(prefix) if !prefix.trim().is_empty() => canonicalize_tag(&prefix).unwrap_or(prefix),
+        _ => String::new(),
+    };
+    let limit = limit.map_or(DEFAULT_TAG_LIST_SIZE, |limit| limit as usize);
+
+    PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        SEARCH_INDEX.with(|si| {
+            let index = si.borrow();
+            let mut tags: Vec<TagCount> = index
+                .tags
+                .range(prefix.clone()..)
+                .take_while(|(tag, _)| tag.starts_with(&prefix))
+                .map(|(tag, prompt_ids)| TagCount {
+                    tag: tag.clone(),
+                    count: prompt_ids
+                        .iter()
+                        .filter(|id| prompts.get(id).is_some_and(is_listed))
+                        .count() as u64,
+                })
+                .filter(|tag| tag.count > 0)
+                .collect();
+            tags.sort_by(|a, b| b.count.cmp(&a.count).then(a.tag.cmp(&b.tag)));
+            tags.truncate(limit);
+            Ok(tags)
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn get_prompts_by_tag(
+    tag: String,
+    page: PageRequest,
+) -> Result<Page<PromptSummary>, PromptVaultError> {
+    let tag =
+        canonicalize_tag(&tag).map_err(|reason| PromptVaultError::validation("tag", reason))?;
+
+    PROMPTS.with(|p| {
+        let prompts = p.borrow();
+        let tagged: Vec<&Prompt> = SEARCH_INDEX.with(|si| {
+            let index = si.borrow();
+            index
+                .tags
+                .get(&tag)
+                .map(|prompt_ids| {
+                    prompt_ids
+                        .iter()
+                        .filter_map(|id| prompts.get(id))
+                        .filter(|prompt| is_listed(prompt))
+                        .collect()
+                })
+                .unwrap_or_default()
+        });
+
+        Ok(paginate(tagged, &page, PromptSummary::from))
+    })
+}
+
+#[ic_cdk::query]
+fn get_user_balance(user_id: UserId) -> Result<u64, PromptVaultError> {
+    USERS.with(|u| {
+        let users = u.borrow();
+        users
+            .get(&user_id)
+            .map(|user| user.balance)
+            .ok_or_else(|| PromptVaultError::not_found("User"))
+    })
+}
+
+#[ic_cdk::update]
+async fn get_user_ledger_balance(user_id: UserId) -> Result<u64, PromptVaultError> {
+    // Create account for the user
+    let account = Account {
+        owner: user_id,
+        subaccount: None,
+    };
+
+    Ok(ledger_balance_of(account).await?)
+}
+
+#[ic_cdk::query]
+fn get_deposit_account() -> Result<Account, PromptVaultError> {
+    Ok(deposit_account(get_caller()))
+}
+
+// Sweeps everything in the caller's deposit subaccount into their internal balance
+#[ic_cdk::update]
+async fn sync_deposit() -> Result<User, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    let user_exists = USERS.with(|u| {
+        let users = u.borrow();
+        users.contains_key(&caller)
+    });
+
+    if !user_exists {
+        return Err(PromptVaultError::not_found("User"));
+    }
+
+    let _guard = BalanceGuard::new(caller)?;
+
+    let sweep = async {
+        let fee = ledger_fee().await?;
+        let available = ledger_balance_of(deposit_account(caller)).await?;
+        if available <= fee {
+            return Ok(0);
+        }
+        let amount = available - fee;
+        ledger_transfer(TransferArg {
+            from_subaccount: Some(deposit_subaccount(caller)),
+            to: escrow_account(),
+            amount: Nat::from(amount),
+            fee: Some(Nat::from(fee)),
+            memo: None,
+            created_at_time: Some(get_time()),
+        })
+        .await?;
+        Ok::<u64, PaymentError>(amount)
+    };
+    let amount = sweep.await?;
+
+    USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        match users.get_mut(&caller) {
+            Some(user) => {
+                user.balance += amount;
+                Ok(user.clone())
+            }
+            None => Err(PromptVaultError::not_found("User")),
+        }
+    })
+}
+
+// Pays `amount` out of the caller's internal balance; the ledger fee is charged on top
+#[ic_cdk::update]
+async fn withdraw(amount: u64, to: Account) -> Result<u64, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    if amount == 0 {
+        return Err(PromptVaultError::validation(
+            "amount",
+            "Amount must be greater than zero",
+        ));
+    }
+
+    let _guard = BalanceGuard::new(caller)?;
+
+    let fee = ledger_fee().await?;
+
+    let required = amount.saturating_add(fee);
+    let available = USERS
+        .with(|u| u.borrow().get(&caller).map(|user| user.balance))
+        .ok_or_else(|| PromptVaultError::not_found("User"))?;
+    if available < required {
+        return Err(PromptVaultError::InsufficientFunds {
+            required,
+            available,
+        });
+    }
+
+    let block_index = ledger_transfer(TransferArg {
+        from_subaccount: None,
+        to,
+        amount: Nat::from(amount),
+        fee: Some(Nat::from(fee)),
+        memo: None,
+        created_at_time: Some(get_time()),
+    })
+    .await?;
+
+    USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        if let Some(user) = users.get_mut(&caller) {
+            user.balance = user.balance.saturating_sub(required);
+        }
+    });
+    Ok(block_index)
+}
+
+#[ic_cdk::query]
+fn get_config() -> Result<Config, PromptVaultError> {
+    Ok(CONFIG.with(|c| c.borrow().clone()))
+}
+
+#[ic_cdk::update]
+fn set_config(update: ConfigUpdate) -> Result<Config, PromptVaultError> {
+    require_role(Role::Admin)?;
+
+    apply_config_update(update)
+}
+
+#[ic_cdk::query]
+fn get_fee_totals() -> Result<FeeTotals, PromptVaultError> {
+    Ok(FEE_TOTALS.with(|f| f.borrow().clone()))
+}
+
+// Sends all platform fees not yet withdrawn from escrow to the treasury account
+#[ic_cdk::update]
+async fn withdraw_platform_fees() -> Result<u64, PromptVaultError> {
+    require_role(Role::Admin)?;
+
+    let treasury = CONFIG
+        .with(|c| c.borrow().treasury.clone())
+        .ok_or_else(|| PromptVaultError::conflict("Treasury account is not configured"))?;
+
+    // Fee withdrawals lock the canister's own balance slot
+    let _guard = BalanceGuard::new(ic_cdk::id())?;
+
+    let fee = ledger_fee().await?;
+
+    let pending = FEE_TOTALS.with(|f| {
+        let totals = f.borrow();
+        totals.fees_collected - totals.fees_withdrawn
+    });
+    if pending <= fee {
+        return Err(PromptVaultError::conflict(format!(
+            "Pending fees of {} e8s do not cover the ledger fee",
+            pending
+        )));
+    }
+
+    let block_index = ledger_transfer(TransferArg {
+        from_subaccount: None,
+        to: treasury,
+        amount: Nat::from(pending - fee),
+        fee: Some(Nat::from(fee)),
+        memo: None,
+        created_at_time: Some(get_time()),
+    })
+    .await?;
+
+    FEE_TOTALS.with(|f| f.borrow_mut().fees_withdrawn += pending);
+    Ok(block_index)
+}
+
+#[ic_cdk::update]
+fn grant_role(user_id: UserId, role: Role) -> Result<RoleAssignment, PromptVaultError> {
+    let caller = require_role(Role::Admin)?;
+
+    if user_id == Principal::anonymous() {
+        return Err(PromptVaultError::validation(
+            "user_id",
+            "Cannot grant a role to the anonymous principal",
+        ));
+    }
+
+    Ok(assign_role(user_id, role, caller))
+}
+
+#[ic_cdk::update]
+fn revoke_role(user_id: UserId) -> Result<RoleAssignment, PromptVaultError> {
+    let caller = require_role(Role::Admin)?;
+
+    if user_id == caller {
+        return Err(PromptVaultError::forbidden("Cannot revoke your own role"));
+    }
+
+    ROLES
+        .with(|r| r.borrow_mut().remove(&user_id))
+        .ok_or_else(|| PromptVaultError::not_found("Role"))
+}
+
+// Granted roles, oldest first; controllers are admins without being listed
+#[ic_cdk::query]
+fn list_roles() -> Result<Vec<RoleAssignment>, PromptVaultError> {
+    require_role(Role::Moderator)?;
+
+    ROLES.with(|r| {
+        let roles = r.borrow();
+        let mut assignments: Vec<RoleAssignment> = roles.values().cloned().collect();
+        assignments.sort_by_key(|assignment| (assignment.granted_at, assignment.user_id));
+        Ok(assignments)
+    })
+}
+
+#[ic_cdk::query]
+fn get_my_role() -> Result<Option<Role>, PromptVaultError> {
+    let caller = get_caller();
+    Ok(if has_role(caller, &Role::Admin) {
+        Some(Role::Admin)
+    } else if has_role(caller, &Role::Moderator) {
+        Some(Role::Moderator)
+    } else {
+        None
+    })
+}
+
+#[ic_cdk::update]
+fn report_prompt(
+    prompt_id: PromptId,
+    reason: ReportReason,
+    details: Option<String>,
+) -> Result<Report, PromptVaultError> {
+    let caller = get_caller();
+    require_active(caller)?;
+
+    if caller == Principal::anonymous() {
+        return Err(PromptVaultError::Unauthorized);
+    }
+
+    if let Some(ref details) = details {
+        if details.len() > MAX_REPORT_DETAILS_LENGTH {
+            return Err(PromptVaultError::validation(
+                "details",
+                format!(
+                    "Details cannot exceed {} characters",
+                    MAX_REPORT_DETAILS_LENGTH
+                ),
+            ));
+        }
+    }
+    if reason == ReportReason::Other
+        && details
+            .as_ref()
+            .is_none_or(|details| details.trim().is_empty())
+    {
+        return Err(PromptVaultError::validation(
+            "details",
+            "Details are required when the reason is Other",
+        ));
+    }
+
+    let author = PROMPTS
+        .with(|p| {
+            let prompts = p.borrow();
+            prompts
+                .get(&prompt_id)
+                .filter(|prompt| prompt.deleted_at.is_none())
+                .map(|prompt| prompt.author)
+        })
+        .ok_or_else(|| PromptVaultError::not_found("Prompt"))?;
+
+    if author == caller {
+        return Err(PromptVaultError::forbidden("Cannot report your own prompt"));
+    }
+
+    let already_reported = REPORTS.with(|r| {
+        let reports = r.borrow();
+        reports.values().any(|report| {
+            report.prompt_id == prompt_id
+                && report.reporter == caller
+                && report.resolution.is_none()
+        })
+    });
+
+    if already_reported {
+        return Err(PromptVaultError::AlreadyExists {
+            resource: "Report".to_string(),
+        });
+    }
+
+    let report_id = NEXT_REPORT_ID.with(|id| {
+        let mut next_id = id.borrow_mut();
+        let current_id = *next_id;
+        *next_id += 1;
+        current_id
+    });
+
+    let report = Report {
+        id: report_id,
+        prompt_id,
+        author,
+        reporter: caller,
+        reason,
+        details,
+        created_at: get_time(),
+        resolution: None,
+    };
+
+    REPORTS.with(|r| {
+        r.borrow_mut().insert(report_id, report.clone());
+    });
+
+    Ok(report)
+}
+
+// Unresolved reports, oldest first
+#[ic_cdk::query]
+fn get_moderation_queue(page: PageRequest) -> Result<Page<Report>, PromptVaultError> {
+    require_role(Role::Moderator)?;
+
+    REPORTS.with(|r| {
+        let reports = r.borrow();
+        let open: Vec<&Report> = reports
+            .values()
+            .filter(|report| report.resolution.is_none())
+            .collect();
+        Ok(paginate(open, &page, Report::clone))
+    })
+}
+
+// Every report filed against a prompt, resolved ones included
+#[ic_cdk::query]
+fn get_prompt_reports(prompt_id: PromptId) -> Result<Vec<Report>, PromptVaultError> {
+    require_role(Role::Moderator)?;
+
+    REPORTS.with(|r| {
+        let reports = r.borrow();
+        Ok(reports
+            .values()
+            .filter(|report| report.prompt_id == prompt_id)
+            .cloned()
+            .collect())
+    })
+}
+
+// Applies the action and records it on the report; hiding or removing the prompt also
+// resolves the other open reports against it
+#[ic_cdk::update]
+fn resolve_report(
+    report_id: ReportId,
+    action: ModerationAction,
+    note: Option<String>,
+) -> Result<Report, PromptVaultError> {
+    let caller = require_role(Role::Moderator)?;
+
+    if note
+        .as_ref()
+        .is_some_and(|note| note.len() > MAX_MODERATION_NOTE_LENGTH)
+    {
+        return Err(PromptVaultError::validation(
+            "note",
+            format!(
+                "Note cannot exceed {} characters",
+                MAX_MODERATION_NOTE_LENGTH
+            ),
+        ));
+    }
+
+    let report = REPORTS
+        .with(|r| r.borrow().get(&report_id).cloned())
+        .ok_or_else(|| PromptVaultError::not_found("Report"))?;
+
+    if report.resolution.is_some() {
+        return Err(PromptVaultError::conflict(
+            "Report has already been resolved",
+        ));
+    }
+
+    let now = get_time();
+    match action {
+        ModerationAction::Dismiss => {}
+        ModerationAction::HidePrompt => {
+            PROMPTS.with(|p| {
+                let mut prompts = p.borrow_mut();
+                if let Some(prompt) = prompts.get_mut(&report.prompt_id) {
+                    prompt.hidden_at.get_or_insert(now);
+                }
+            });
+        }
+        ModerationAction::RemovePrompt => {
+            // Same rule as delete_prompt: taking a prompt away from its buyers is an admin call
+            let has_buyers = PROMPTS.with(|p| {
+                p.borrow()
+                    .get(&report.prompt_id)
+                    .is_some_and(|prompt| prompt.purchases > 0)
+            });
+            if has_buyers && !has_role(caller, &Role::Admin) {
+                return Err(PromptVaultError::Unauthorized);
+            }
+            remove_prompt(report.prompt_id)?;
+        }
+        ModerationAction::WarnAuthor => {
+            USERS.with(|u| {
+                let mut users = u.borrow_mut();
+                if let Some(user) = users.get_mut(&report.author) {
+                    user.warnings += 1;
+                }
+            });
+        }
+        ModerationAction::SuspendAuthor { until } => {
+            apply_user_status(caller, report.author, UserStatus::Suspended { until })?;
+        }
+    }
+
+    let closes_prompt = matches!(
+        action,
+        ModerationAction::HidePrompt | ModerationAction::RemovePrompt
+    );
+    let resolution = Resolution {
+        action,
+        moderator: caller,
+        note,
+        resolved_at: now,
+    };
+
+    REPORTS.with(|r| {
+        let mut reports = r.borrow_mut();
+        for other in reports.values_mut() {
+            let affected =
+                other.id == report_id || (closes_prompt && other.prompt_id == report.prompt_id);
+            if affected && other.resolution.is_none() {
+                other.resolution = Some(resolution.clone());
+            }
         }
+        reports
+            .get(&report_id)
+            .cloned()
+            .ok_or_else(|| PromptVaultError::not_found("Report"))
     })
 }
 
 #[ic_cdk::update]
-async fn get_user_ledger_balance(user_id: UserId) -> ApiResponse<u64> {
-    // Ledger canister ID on mainnet
-    let ledger_canister_id = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+fn unhide_prompt(prompt_id: PromptId) -> Result<PromptSummary, PromptVaultError> {
+    let caller = require_role(Role::Moderator)?;
 
-    // Create account for the user
-    let account = Account {
-        owner: user_id,
-        subaccount: None,
-    };
+    PROMPTS.with(|p| {
+        let mut prompts = p.borrow_mut();
+        let prompt = prompts
+            .get_mut(&prompt_id)
+            .ok_or_else(|| PromptVaultError::not_found("Prompt"))?;
+        prompt.hidden_at = None;
+        Ok(summary_for(prompt, caller))
+    })
+}
 
-    // Call the ledger to get balance
-    match ic_cdk::call::<(Account,), (AccountBalance,)>(
-        ledger_canister_id,
-        "account_balance",
-        (account,),
-    )
-    .await
-    {
-        Ok((balance,)) => ApiResponse {
-            success: true,
-            data: Some(balance.e8s),
-            error: None,
-        },
-        Err(error) => ApiResponse {
-            success: false,
-            data: None,
-            error: Some(format!("Failed to get ledger balance: {:?}", error)),
-        },
+#[ic_cdk::update]
+fn set_user_status(user_id: UserId, status: UserStatus) -> Result<User, PromptVaultError> {
+    let caller = require_role(Role::Moderator)?;
+    apply_user_status(caller, user_id, status)
+}
+
+// Shared by set_user_status and SuspendAuthor resolutions. Moderators restrict regular
+// users and only admins restrict other staff; a ban is lifted by setting Active, never
+// shortened into a suspension.
+fn apply_user_status(
+    moderator: UserId,
+    user_id: UserId,
+    status: UserStatus,
+) -> Result<User, PromptVaultError> {
+    if user_id == moderator {
+        return Err(PromptVaultError::forbidden("Cannot change your own status"));
+    }
+    if has_role(user_id, &Role::Moderator) && !has_role(moderator, &Role::Admin) {
+        return Err(PromptVaultError::Unauthorized);
     }
+    if let UserStatus::Suspended { until } = status {
+        if until <= get_time() {
+            return Err(PromptVaultError::validation(
+                "until",
+                "Suspension must end in the future",
+            ));
+        }
+    }
+
+    USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        let user = users
+            .get_mut(&user_id)
+            .ok_or_else(|| PromptVaultError::not_found("User"))?;
+        if user.status == UserStatus::Banned && matches!(status, UserStatus::Suspended { .. }) {
+            return Err(PromptVaultError::conflict(
+                "User is banned; lift the ban before suspending them",
+            ));
+        }
+        user.status = status;
+        Ok(user.clone())
+    })
 }
 
 #[ic_cdk::update]
-fn update_username(new_username: String) -> ApiResponse<User> {
+fn update_username(new_username: String) -> Result<User, PromptVaultError> {
     let caller = get_caller();
-    if new_username.trim().is_empty() || new_username.len() > 50 {
-        return ApiResponse {
-            success: false,
-            data: None,
-            error: Some("Username must be between 1 and 50 characters".to_string()),
-        };
-    }
+    require_active(caller)?;
+    validate_username(&new_username)?;
     USERS.with(|u| {
         let mut users = u.borrow_mut();
         match users.get_mut(&caller) {
             Some(user) => {
                 user.username = Some(new_username.clone());
-                ApiResponse {
-                    success: true,
-                    data: Some(user.clone()),
-                    error: None,
-                }
+                Ok(user.clone())
             }
-            None => ApiResponse {
-                success: false,
-                data: None,
-                error: Some("User not found".to_string()),
-            },
+            None => Err(PromptVaultError::not_found("User")),
         }
     })
 }
+
+// Pre-PromptVaultError API: same calls answering with ApiResponse, kept while clients migrate
+#[ic_cdk::update]
+fn create_user_v1(username: Option<String>, email: Option<String>) -> ApiResponse<User> {
+    create_user(username, email).into()
+}
+
+#[ic_cdk::query]
+fn get_user_v1(user_id: UserId) -> ApiResponse<User> {
+    get_user(user_id).into()
+}
+
+#[ic_cdk::update]
+fn update_username_v1(new_username: String) -> ApiResponse<User> {
+    update_username(new_username).into()
+}
+
+#[ic_cdk::update]
+fn create_prompt_v1(request: CreatePromptRequest) -> ApiResponse<Prompt> {
+    create_prompt(request).into()
+}
+
+#[ic_cdk::query]
+fn get_prompt_v1(prompt_id: PromptId) -> ApiResponse<PromptSummary> {
+    get_prompt(prompt_id).into()
+}
+
+#[ic_cdk::query]
+fn get_public_prompts_v1(sort: Option<SortOptions>) -> ApiResponse<Vec<PromptSummary>> {
+    get_public_prompts(sort).into()
+}
+
+#[ic_cdk::query]
+fn get_user_prompts_v1(user_id: UserId) -> ApiResponse<Vec<PromptSummary>> {
+    get_user_prompts(user_id).into()
+}
+
+#[ic_cdk::query]
+fn get_public_prompts_page_v1(
+    page: PageRequest,
+    sort: Option<SortOptions>,
+) -> ApiResponse<Page<PromptSummary>> {
+    get_public_prompts_page(page, sort).into()
+}
+
+#[ic_cdk::query]
+fn get_user_prompts_page_v1(
+    user_id: UserId,
+    page: PageRequest,
+) -> ApiResponse<Page<PromptSummary>> {
+    get_user_prompts_page(user_id, page).into()
+}
+
+#[ic_cdk::update]
+fn update_prompt_v1(request: UpdatePromptRequest) -> ApiResponse<Prompt> {
+    update_prompt(request).into()
+}
+
+#[ic_cdk::update]
+fn delete_prompt_v1(prompt_id: PromptId) -> ApiResponse<String> {
+    delete_prompt(prompt_id).into()
+}
+
+#[ic_cdk::update]
+fn admin_delete_prompt_v1(prompt_id: PromptId) -> ApiResponse<String> {
+    admin_delete_prompt(prompt_id).into()
+}
+
+#[ic_cdk::query]
+fn get_prompt_content_v1(prompt_id: PromptId) -> ApiResponse<String> {
+    get_prompt_content(prompt_id)
+        .map(|content| content.flattened)
+        .into()
+}
+
+#[ic_cdk::update]
+async fn purchase_prompt_v1(
+    prompt_id: PromptId,
+    method: Option<PaymentMethod>,
+) -> Result<Purchase, PurchaseError> {
+    purchase_prompt(prompt_id, method)
+        .await
+        .map_err(PurchaseError::from)
+}
+
+#[ic_cdk::query]
+fn get_user_purchases_v1(user_id: UserId) -> ApiResponse<Vec<PromptId>> {
+    get_user_purchases(user_id).into()
+}
+
+#[ic_cdk::update]
+fn like_prompt_v1(prompt_id: PromptId) -> ApiResponse<String> {
+    like_prompt(prompt_id).into()
+}
+
+#[ic_cdk::update]
+fn unlike_prompt_v1(prompt_id: PromptId) -> ApiResponse<String> {
+    unlike_prompt(prompt_id).into()
+}
+
+#[ic_cdk::update]
+fn rate_prompt_v1(request: RatePromptRequest) -> ApiResponse<String> {
+    rate_prompt(request).into()
+}
+
+#[ic_cdk::query]
+fn get_user_balance_v1(user_id: UserId) -> ApiResponse<u64> {
+    get_user_balance(user_id).into()
+}
+
+#[ic_cdk::update]
+async fn get_user_ledger_balance_v1(user_id: UserId) -> ApiResponse<u64> {
+    get_user_ledger_balance(user_id).await.into()
+}
+
+#[ic_cdk::update]
+async fn sync_deposit_v1() -> ApiResponse<User> {
+    sync_deposit().await.into()
+}
+
+#[ic_cdk::update]
+async fn withdraw_v1(amount: u64, to: Account) -> ApiResponse<u64> {
+    withdraw(amount, to).await.into()
+}
+
+#[ic_cdk::update]
+fn set_config_v1(update: ConfigUpdate) -> ApiResponse<Config> {
+    set_config(update).into()
+}
+
+#[ic_cdk::update]
+async fn withdraw_platform_fees_v1() -> ApiResponse<u64> {
+    withdraw_platform_fees().await.into()
+}
+
+#[ic_cdk::query]
+fn search_prompts_v1(
+    query: String,
+    category: Option<PromptCategory>,
+    sort: Option<SortOptions>,
+) -> ApiResponse<Vec<PromptSummary>> {
+    search_prompts(query, category, sort).into()
+}
+
+#[ic_cdk::query]
+fn search_prompts_page_v1(
+    query: String,
+    category: Option<PromptCategory>,
+    page: PageRequest,
+    sort: Option<SortOptions>,
+) -> ApiResponse<Page<PromptSummary>> {
+    search_prompts_page(query, category, page, sort).into()
+}
+
+#[ic_cdk::query]
+fn search_prompts_ranked_v1(query: SearchQuery, page: PageRequest) -> ApiResponse<Page<SearchHit>> {
+    search_prompts_ranked(query, page).into()
+}
+
+#[ic_cdk::query]
+fn get_prompts_by_tag_v1(tag: String, page: PageRequest) -> ApiResponse<Page<PromptSummary>> {
+    get_prompts_by_tag(tag, page).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn prompt(id: PromptId, title: &str, description: &str, tags: &[&str]) -> Prompt {
+        Prompt {
+            id,
+            title: title.to_string(),
+            description: description.to_string(),
+            content: format!("Content of {}", title),
+            author: principal(1),
+            category: PromptCategory::Development,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            price: 0,
+            is_premium: false,
+            is_public: true,
+            created_at: 100,
+            updated_at: 200,
+            likes: 0,
+            purchases: 0,
+            rating: 0.0,
+            total_ratings: 0,
+            rating_sum: 0,
+            rating_counts: [0; 5],
+            deleted_at: None,
+            revision: 1,
+            variables: Vec::new(),
+            messages: Vec::new(),
+            hidden_at: None,
+        }
+    }
+
+    fn user(id: UserId, joined_at: u64) -> User {
+        User {
+            id,
+            username: None,
+            email: None,
+            joined_at,
+            total_earnings: 0,
+            total_spent: 0,
+            prompts_created: 0,
+            prompts_purchased: 0,
+            balance: 0,
+            status: UserStatus::Active,
+            warnings: 0,
+            follower_count: 0,
+            following_count: 0,
+        }
+    }
+
+    fn review(
+        prompt_id: PromptId,
+        reviewer: UserId,
+        rating: u8,
+        verified_purchase: bool,
+        updated_at: u64,
+    ) -> Review {
+        Review {
+            prompt_id,
+            reviewer,
+            rating,
+            text: None,
+            verified_purchase,
+            created_at: updated_at,
+            updated_at,
+            reply: None,
+            helpful_votes: 0,
+            unhelpful_votes: 0,
+        }
+    }
+
+    fn sale(buyer: UserId, seller: UserId) -> Purchase {
+        Purchase {
+            prompt_id: 1,
+            buyer,
+            seller,
+            price: 1_000,
+            timestamp: 300,
+            block_index: Some(42),
+            seller_amount: 975,
+            fee_amount: 25,
+            ledger_fee: 10,
+        }
+    }
+
+    fn variable(name: &str) -> TemplateVariable {
+        TemplateVariable {
+            name: name.to_string(),
+            description: None,
+            default_value: None,
+            required: true,
+            options: None,
+        }
+    }
+
+    fn sample_index() -> SearchIndex {
+        let mut index = SearchIndex::default();
+        index.insert(&prompt(
+            1,
+            "Rust code reviewer",
+            "Reviews pull requests",
+            &["rust"],
+        ));
+        index.insert(&prompt(
+            2,
+            "Essay helper",
+            "Works with rust snippets too",
+            &["writing"],
+        ));
+        index.insert(&prompt(
+            3,
+            "Python tutor",
+            "Explains python code",
+            &["python"],
+        ));
+        index
+    }
+
+    // Matching prompt ids, best score first
+    fn ranked(scores: HashMap<PromptId, f64>) -> Vec<PromptId> {
+        let mut scores: Vec<(PromptId, f64)> = scores.into_iter().collect();
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        scores.into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn search_ranks_title_matches_above_description_matches() {
+        let index = sample_index();
+        let scores = index.search(&parse_query("rust", false), &MatchMode::All);
+        assert_eq!(ranked(scores), vec![1, 2]);
+    }
+
+    #[test]
+    fn search_match_modes() {
+        let index = sample_index();
+        let terms = parse_query("python code", false);
+        assert_eq!(ranked(index.search(&terms, &MatchMode::All)), vec![3]);
+        assert_eq!(ranked(index.search(&terms, &MatchMode::Any)), vec![3, 1]);
+    }
+
+    #[test]
+    fn search_matches_prefixes() {
+        let index = sample_index();
+        // Only the last term is a prefix unless marked with `*` or prefix_all is set
+        assert_eq!(
+            ranked(index.search(&parse_query("pyt", false), &MatchMode::All)),
+            vec![3]
+        );
+        assert_eq!(
+            ranked(index.search(&parse_query("pyt rev", false), &MatchMode::Any)),
+            vec![1]
+        );
+        let expected = vec![1, 3];
+        let mut any = ranked(index.search(&parse_query("pyt* rev", false), &MatchMode::Any));
+        any.sort();
+        assert_eq!(any, expected);
+        let mut all_prefix = ranked(index.search(&parse_query("pyt rev", true), &MatchMode::Any));
+        all_prefix.sort();
+        assert_eq!(all_prefix, expected);
+    }
+
+    #[test]
+    fn search_forgets_removed_prompts() {
+        let mut index = sample_index();
+        index.remove(1);
+        let scores = index.search(&parse_query("rust", false), &MatchMode::All);
+        assert_eq!(ranked(scores), vec![2]);
+        assert!(!index.tags.contains_key("rust"));
+
+        index.remove(2);
+        index.remove(3);
+        assert_eq!(index.total_length, 0);
+        assert!(index.postings.is_empty());
+        assert!(index.lengths.is_empty());
+        assert!(index.tags.is_empty());
+        assert!(index
+            .search(&parse_query("python", false), &MatchMode::Any)
+            .is_empty());
+    }
+
+    #[test]
+    fn paginate_walks_pages_and_ignores_out_of_range_cursors() {
+        let page = |cursor: Option<u64>, limit: Option<u32>| {
+            paginate(
+                (0..45).collect(),
+                &PageRequest { cursor, limit },
+                |i: u32| i,
+            )
+        };
+        let first = page(None, None);
+        assert_eq!(first.items, (0..20).collect::<Vec<_>>());
+        assert_eq!((first.next_cursor, first.total), (Some(20), 45));
+        let last = page(Some(40), Some(10));
+        assert_eq!(last.items, (40..45).collect::<Vec<_>>());
+        assert_eq!(last.next_cursor, None);
+        assert_eq!(page(None, Some(0)).items, vec![0]);
+        let capped = paginate(
+            (0..250).collect(),
+            &PageRequest {
+                cursor: None,
+                limit: Some(1_000),
+            },
+            |i: u32| i,
+        );
+        assert_eq!(capped.items.len(), MAX_PAGE_SIZE);
+
+        for cursor in [45, 1 << 32, (1 << 32) + 5, u64::MAX] {
+            let past_end = page(Some(cursor), None);
+            assert!(past_end.items.is_empty());
+            assert_eq!(past_end.next_cursor, None);
+        }
+    }
+
+    #[test]
+    fn canonicalize_tag_normalizes_case_and_separators() {
+        assert_eq!(
+            canonicalize_tag("  Machine Learning ").unwrap(),
+            "machine-learning"
+        );
+        assert_eq!(canonicalize_tag("snake_case").unwrap(), "snake-case");
+        assert_eq!(canonicalize_tag("C++").unwrap(), "c++");
+        assert_eq!(canonicalize_tag("GPT-4.5").unwrap(), "gpt-4.5");
+        assert!(canonicalize_tag("   ").is_err());
+        assert!(canonicalize_tag("emoji🙂").is_err());
+        assert!(canonicalize_tag(&"a".repeat(MAX_TAG_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn normalize_tags_drops_duplicates_and_caps_the_count() {
+        let tags: Vec<String> = ["AI", "ai", " Ai ", "Prompt Design", "prompt_design"]
+            .iter()
+            .map(|tag| tag.to_string())
+            .collect();
+        assert_eq!(normalize_tags(&tags).unwrap(), vec!["ai", "prompt-design"]);
+
+        let too_many: Vec<String> = (0..=MAX_TAGS).map(|i| format!("tag{}", i)).collect();
+        assert!(normalize_tags(&too_many).is_err());
+        // Duplicates don't count towards the cap
+        let mut repeated = vec!["same".to_string(); MAX_TAGS + 1];
+        repeated.push("other".to_string());
+        assert_eq!(normalize_tags(&repeated).unwrap(), vec!["same", "other"]);
+    }
+
+    #[test]
+    fn template_segments_only_treat_variable_names_as_placeholders() {
+        let segments: Vec<String> =
+            template_segments("Hi {{ name }}, {{not valid}} and {{ 1x }} {{x}}")
+                .into_iter()
+                .map(|segment| match segment {
+                    TemplateSegment::Text(text) => text.to_string(),
+                    TemplateSegment::Placeholder(name) => format!("<{}>", name),
+                })
+                .collect();
+        assert_eq!(
+            segments,
+            vec![
+                "Hi ",
+                "<name>",
+                ", {{",
+                "not valid}} and {{",
+                " 1x }} ",
+                "<x>",
+                ""
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_variables_requires_placeholders_and_declarations_to_match() {
+        let field_of = |result: Result<(), PromptVaultError>| match result {
+            Err(PromptVaultError::Validation { field, .. }) => Some(field),
+            _ => None,
+        };
+        let content = "Write about {{topic}} in a {{ tone }} tone";
+
+        // Plain content: placeholders aren't checked until variables are declared
+        assert!(validate_variables(content, &[]).is_ok());
+        assert!(validate_variables(content, &[variable("topic"), variable("tone")]).is_ok());
+        assert_eq!(
+            field_of(validate_variables(content, &[variable("topic")])).as_deref(),
+            Some("content")
+        );
+        assert_eq!(
+            field_of(validate_variables(
+                "{{topic}}",
+                &[variable("topic"), variable("tone")]
+            ))
+            .as_deref(),
+            Some("variables")
+        );
+        assert!(validate_variables("{{topic}}", &[variable("topic"), variable("topic")]).is_err());
+        assert!(validate_variables("{{1st}}", &[variable("1st")]).is_err());
+
+        let mut choice = variable("tone");
+        choice.options = Some(Vec::new());
+        assert!(validate_variables("{{tone}}", &[choice]).is_err());
+    }
+
+    #[test]
+    fn update_rating_aggregates_tracks_counts_and_average() {
+        let mut prompt = prompt(1, "Rated", "", &[]);
+        update_rating_aggregates(&mut prompt, None, Some(5));
+        update_rating_aggregates(&mut prompt, None, Some(4));
+        update_rating_aggregates(&mut prompt, None, Some(1));
+        assert_eq!(prompt.rating_counts, [1, 0, 0, 1, 1]);
+        assert_eq!(prompt.rating_sum, 10);
+        assert_eq!(prompt.total_ratings, 3);
+        assert!((prompt.rating - 10.0 / 3.0).abs() < 1e-9);
+
+        // Changing a rating moves it between buckets
+        update_rating_aggregates(&mut prompt, Some(1), Some(3));
+        assert_eq!(prompt.rating_counts, [0, 0, 1, 1, 1]);
+        assert_eq!(prompt.rating, 4.0);
+
+        update_rating_aggregates(&mut prompt, Some(5), None);
+        assert_eq!(prompt.rating_sum, 7);
+        assert_eq!(prompt.total_ratings, 2);
+        assert_eq!(prompt.rating, 3.5);
+
+        // Out-of-range ratings are ignored and removals never underflow
+        update_rating_aggregates(&mut prompt, None, Some(0));
+        update_rating_aggregates(&mut prompt, None, Some(6));
+        update_rating_aggregates(&mut prompt, Some(1), None);
+        assert_eq!(prompt.rating_counts, [0, 0, 1, 1, 0]);
+        assert_eq!(prompt.total_ratings, 2);
+
+        let mut empty = self::prompt(2, "Unrated", "", &[]);
+        update_rating_aggregates(&mut empty, Some(4), None);
+        assert_eq!(empty.rating_counts, [0; 5]);
+        assert_eq!(
+            (empty.rating_sum, empty.total_ratings, empty.rating),
+            (0, 0, 0.0)
+        );
+    }
+
+    #[test]
+    fn changing_rating_eligibility_recounts_existing_ratings() {
+        const SECOND: u64 = 1_000_000_000;
+        let (veteran, newcomer) = (principal(2), principal(3));
+        PROMPTS.with(|p| p.borrow_mut().insert(1, prompt(1, "Rated", "", &[])));
+        USERS.with(|u| {
+            let mut users = u.borrow_mut();
+            users.insert(veteran, user(veteran, 0));
+            users.insert(newcomer, user(newcomer, 90 * SECOND));
+        });
+        // The veteran rated 5 without buying; the newcomer bought and rated 1 at 100s
+        USER_RATINGS.with(|ur| {
+            let mut ratings = ur.borrow_mut();
+            ratings.insert(veteran, HashMap::from([(1, 5)]));
+            ratings.insert(newcomer, HashMap::from([(1, 1)]));
+        });
+        REVIEWS.with(|r| {
+            r.borrow_mut().insert(
+                1,
+                HashMap::from([
+                    (veteran, review(1, veteran, 5, false, 100 * SECOND)),
+                    (newcomer, review(1, newcomer, 1, true, 100 * SECOND)),
+                ]),
+            )
+        });
+        let aggregates = || {
+            PROMPTS.with(|p| {
+                let prompts = p.borrow();
+                (prompts[&1].total_ratings, prompts[&1].rating)
+            })
+        };
+        let set_policy = |policy: RatingEligibility| {
+            apply_config_update(ConfigUpdate {
+                rating_eligibility: Some(policy),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+
+        set_policy(RatingEligibility::Buyers);
+        assert_eq!(aggregates(), (1, 1.0));
+        set_policy(RatingEligibility::MinAccountAge { seconds: 60 });
+        assert_eq!(aggregates(), (1, 5.0));
+        assert_eq!(counted_rating(veteran, 1), Some(5));
+        assert_eq!(counted_rating(newcomer, 1), None);
+        // Excluded ratings were kept, so relaxing the policy counts them again
+        set_policy(RatingEligibility::Anyone);
+        assert_eq!(aggregates(), (2, 3.0));
+    }
+
+    #[test]
+    fn removing_a_prompt_keeps_its_purchase_records() {
+        let (author, buyer) = (principal(1), principal(2));
+        PROMPTS.with(|p| p.borrow_mut().insert(1, prompt(1, "Sold", "", &[])));
+        PURCHASES.with(|p| p.borrow_mut().push(sale(buyer, author)));
+        USER_PURCHASES.with(|up| up.borrow_mut().insert(buyer, vec![1, 2]));
+        USER_LIKES.with(|ul| ul.borrow_mut().insert(buyer, vec![1]));
+
+        PENDING_PURCHASES.with(|pp| pp.borrow_mut().insert((buyer, 1)));
+        assert!(matches!(
+            remove_prompt(1),
+            Err(PromptVaultError::OperationInProgress)
+        ));
+        PENDING_PURCHASES.with(|pp| pp.borrow_mut().clear());
+
+        remove_prompt(1).unwrap();
+        assert!(PROMPTS.with(|p| p.borrow().is_empty()));
+        assert_eq!(PURCHASES.with(|p| p.borrow()[0].block_index), Some(42));
+        assert_eq!(
+            USER_PURCHASES.with(|up| up.borrow()[&buyer].clone()),
+            vec![2]
+        );
+        assert!(USER_LIKES.with(|ul| ul.borrow()[&buyer].is_empty()));
+    }
+
+    #[test]
+    fn purchases_from_older_snapshots_still_decode() {
+        // Older snapshots stored the prompt revision each purchase was made at
+        let mut value = ciborium::Value::serialized(&sale(principal(2), principal(1))).unwrap();
+        if let ciborium::Value::Map(entries) = &mut value {
+            entries.push(("revision".into(), 3.into()));
+        }
+        let purchase: Purchase = value.deserialized().unwrap();
+        assert_eq!(purchase.block_index, Some(42));
+        assert_eq!(purchase.seller_amount, 975);
+    }
+
+    #[test]
+    fn restricted_moderators_lose_their_powers() {
+        let (moderator, admin, member) = (principal(2), principal(3), principal(4));
+        for (user_id, role) in [(moderator, Role::Moderator), (admin, Role::Admin)] {
+            USERS.with(|u| u.borrow_mut().insert(user_id, user(user_id, 0)));
+            ROLES.with(|r| {
+                r.borrow_mut().insert(
+                    user_id,
+                    RoleAssignment {
+                        user_id,
+                        role,
+                        granted_by: principal(1),
+                        granted_at: 0,
+                    },
+                )
+            });
+        }
+        USERS.with(|u| u.borrow_mut().insert(member, user(member, 0)));
+        let set_status = |user_id: UserId, status: UserStatus| {
+            USERS.with(|u| u.borrow_mut().get_mut(&user_id).unwrap().status = status)
+        };
+
+        assert!(check_assigned_role(moderator, &Role::Moderator, 100).is_ok());
+        assert!(check_assigned_role(admin, &Role::Moderator, 100).is_ok());
+        assert!(matches!(
+            check_assigned_role(moderator, &Role::Admin, 100),
+            Err(PromptVaultError::Unauthorized)
+        ));
+        assert!(matches!(
+            check_assigned_role(member, &Role::Moderator, 100),
+            Err(PromptVaultError::Unauthorized)
+        ));
+
+        set_status(moderator, UserStatus::Banned);
+        assert!(matches!(
+            check_assigned_role(moderator, &Role::Moderator, 100),
+            Err(PromptVaultError::AccountRestricted {
+                status: UserStatus::Banned
+            })
+        ));
+
+        // A suspension holds the role back only until it lapses
+        set_status(admin, UserStatus::Suspended { until: 200 });
+        assert!(check_assigned_role(admin, &Role::Admin, 100).is_err());
+        assert!(check_assigned_role(admin, &Role::Admin, 200).is_ok());
+    }
+
+    #[test]
+    fn state_survives_an_upgrade_round_trip() {
+        let (author, buyer, moderator) = (principal(1), principal(2), principal(3));
+        let mut rated = prompt(1, "Rust code reviewer", "Reviews pull requests", &["rust"]);
+        update_rating_aggregates(&mut rated, None, Some(4));
+        let revision = PromptRevision {
+            revision: 1,
+            title: rated.title.clone(),
+            description: rated.description.clone(),
+            content: rated.content.clone(),
+            created_at: 100,
+            note: Some("First version".to_string()),
+            variables: Vec::new(),
+            messages: Vec::new(),
+        };
+        PROMPTS.with(|p| p.borrow_mut().insert(1, rated));
+        USERS.with(|u| {
+            let mut users = u.borrow_mut();
+            users.insert(author, user(author, 10));
+            let mut buyer_record = user(buyer, 20);
+            buyer_record.status = UserStatus::Suspended { until: 5_000 };
+            buyer_record.warnings = 1;
+            users.insert(buyer, buyer_record);
+        });
+        PURCHASES.with(|p| p.borrow_mut().push(sale(buyer, author)));
+        USER_PURCHASES.with(|up| up.borrow_mut().insert(buyer, vec![1]));
+        USER_LIKES.with(|ul| ul.borrow_mut().insert(buyer, vec![1]));
+        USER_RATINGS.with(|ur| ur.borrow_mut().insert(buyer, HashMap::from([(1, 4)])));
+        NEXT_PROMPT_ID.with(|id| *id.borrow_mut() = 2);
+        PROMPT_REVISIONS.with(|pr| pr.borrow_mut().insert(1, vec![revision]));
+        CONFIG.with(|c| {
+            let mut config = c.borrow_mut();
+            config.platform_fee_bps = 250;
+            config.rating_eligibility = RatingEligibility::MinAccountAge { seconds: 60 };
+        });
+        FEE_TOTALS.with(|f| f.borrow_mut().sales_volume = 1_000);
+        ROLES.with(|r| {
+            r.borrow_mut().insert(
+                moderator,
+                RoleAssignment {
+                    user_id: moderator,
+                    role: Role::Moderator,
+                    granted_by: author,
+                    granted_at: 50,
+                },
+            )
+        });
+        REPORTS.with(|r| {
+            r.borrow_mut().insert(
+                1,
+                Report {
+                    id: 1,
+                    prompt_id: 1,
+                    author,
+                    reporter: moderator,
+                    reason: ReportReason::Spam,
+                    details: None,
+                    created_at: 400,
+                    resolution: Some(Resolution {
+                        action: ModerationAction::WarnAuthor,
+                        moderator,
+                        note: None,
+                        resolved_at: 500,
+                    }),
+                },
+            )
+        });
+        NEXT_REPORT_ID.with(|id| *id.borrow_mut() = 2);
+        REVIEWS.with(|r| {
+            r.borrow_mut()
+                .insert(1, HashMap::from([(buyer, review(1, buyer, 4, true, 350))]))
+        });
+        REVIEW_VOTES.with(|rv| {
+            rv.borrow_mut()
+                .insert((1, buyer), HashMap::from([(moderator, true)]))
+        });
+        COMMENTS.with(|c| {
+            c.borrow_mut().insert(
+                1,
+                Comment {
+                    id: 1,
+                    prompt_id: 1,
+                    author: buyer,
+                    parent_id: None,
+                    text: "Works well".to_string(),
+                    created_at: 360,
+                    edited_at: None,
+                    deleted_at: None,
+                    removed_by_moderator: false,
+                    reply_count: 0,
+                },
+            )
+        });
+        NEXT_COMMENT_ID.with(|id| *id.borrow_mut() = 2);
+        USER_FOLLOWING.with(|uf| uf.borrow_mut().insert(buyer, BTreeSet::from([author])));
+
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&take_state(), &mut bytes).unwrap();
+        assert!(PROMPTS.with(|p| p.borrow().is_empty()));
+        let state = load_state(1, bytes.as_slice()).unwrap();
+        restore_state(state).unwrap();
+
+        PROMPTS.with(|p| {
+            let prompts = p.borrow();
+            let prompt = &prompts[&1];
+            assert_eq!(prompt.title, "Rust code reviewer");
+            assert_eq!(prompt.tags, vec!["rust"]);
+            assert_eq!(prompt.rating_counts, [0, 0, 0, 1, 0]);
+            assert_eq!((prompt.rating_sum, prompt.total_ratings), (4, 1));
+        });
+        USERS.with(|u| {
+            let users = u.borrow();
+            assert_eq!(users.len(), 2);
+            assert_eq!(users[&buyer].status, UserStatus::Suspended { until: 5_000 });
+            assert_eq!(users[&buyer].warnings, 1);
+        });
+        PURCHASES.with(|p| {
+            let purchases = p.borrow();
+            assert_eq!(purchases.len(), 1);
+            assert_eq!(purchases[0].block_index, Some(42));
+            assert_eq!(
+                (purchases[0].seller_amount, purchases[0].fee_amount),
+                (975, 25)
+            );
+        });
+        assert!(has_purchased(buyer, 1));
+        assert_eq!(USER_LIKES.with(|ul| ul.borrow()[&buyer].clone()), vec![1]);
+        assert_eq!(USER_RATINGS.with(|ur| ur.borrow()[&buyer][&1]), 4);
+        assert_eq!(NEXT_PROMPT_ID.with(|id| *id.borrow()), 2);
+        PROMPT_REVISIONS.with(|pr| {
+            let revisions = pr.borrow();
+            assert_eq!(revisions[&1].len(), 1);
+            assert_eq!(revisions[&1][0].note.as_deref(), Some("First version"));
+        });
+        CONFIG.with(|c| {
+            let config = c.borrow();
+            assert_eq!(config.platform_fee_bps, 250);
+            assert_eq!(
+                config.rating_eligibility,
+                RatingEligibility::MinAccountAge { seconds: 60 }
+            );
+        });
+        assert_eq!(FEE_TOTALS.with(|f| f.borrow().sales_volume), 1_000);
+        assert_eq!(
+            ROLES.with(|r| r.borrow()[&moderator].role.clone()),
+            Role::Moderator
+        );
+        REPORTS.with(|r| {
+            let reports = r.borrow();
+            let resolution = reports[&1].resolution.as_ref().unwrap();
+            assert_eq!(resolution.action, ModerationAction::WarnAuthor);
+        });
+        assert_eq!(NEXT_REPORT_ID.with(|id| *id.borrow()), 2);
+        REVIEWS.with(|r| {
+            let reviews = r.borrow();
+            assert!(reviews[&1][&buyer].verified_purchase);
+            assert_eq!(reviews[&1][&buyer].updated_at, 350);
+        });
+        assert!(REVIEW_VOTES.with(|rv| rv.borrow()[&(1, buyer)][&moderator]));
+        assert_eq!(COMMENTS.with(|c| c.borrow()[&1].text.clone()), "Works well");
+        assert_eq!(NEXT_COMMENT_ID.with(|id| *id.borrow()), 2);
+        assert!(USER_FOLLOWING.with(|uf| uf.borrow()[&buyer].contains(&author)));
+
+        // Derived state is rebuilt rather than stored
+        assert!(USER_FOLLOWERS.with(|uf| uf.borrow()[&author].contains(&buyer)));
+        let scores = SEARCH_INDEX.with(|si| {
+            si.borrow()
+                .search(&parse_query("reviewer", false), &MatchMode::All)
+        });
+        assert_eq!(ranked(scores), vec![1]);
+    }
+
+    #[test]
+    fn empty_stable_memory_starts_from_the_default_state() {
+        let state = load_state(0, std::io::empty()).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        restore_state(state).unwrap();
+        assert!(PROMPTS.with(|p| p.borrow().is_empty()));
+        assert!(USERS.with(|u| u.borrow().is_empty()));
+        assert_eq!(NEXT_PROMPT_ID.with(|id| *id.borrow()), 1);
+        assert_eq!(NEXT_REPORT_ID.with(|id| *id.borrow()), 1);
+        assert_eq!(NEXT_COMMENT_ID.with(|id| *id.borrow()), 1);
+
+        // Memory that holds something must decode; garbage isn't mistaken for no state
+        assert!(load_state(1, [0xffu8; 4].as_slice()).is_err());
+    }
+
+    // Schema of the first stable snapshot, before any field was added
+    #[derive(Serialize)]
+    struct V1Prompt {
+        id: PromptId,
+        title: String,
+        description: String,
+        content: String,
+        author: UserId,
+        category: PromptCategory,
+        tags: Vec<String>,
+        price: u64,
+        is_premium: bool,
+        is_public: bool,
+        created_at: u64,
+        updated_at: u64,
+        likes: u64,
+        purchases: u64,
+        rating: f64,
+        total_ratings: u64,
+    }
+
+    #[derive(Serialize)]
+    struct V1User {
+        id: UserId,
+        username: Option<String>,
+        email: Option<String>,
+        joined_at: u64,
+        total_earnings: u64,
+        total_spent: u64,
+        prompts_created: u64,
+        prompts_purchased: u64,
+        balance: u64,
+    }
+
+    #[derive(Serialize)]
+    struct V1State {
+        version: u32,
+        prompts: HashMap<PromptId, V1Prompt>,
+        users: HashMap<UserId, V1User>,
+        purchases: Vec<Purchase>,
+        user_purchases: HashMap<UserId, Vec<PromptId>>,
+        user_likes: HashMap<UserId, Vec<PromptId>>,
+        user_ratings: HashMap<UserId, HashMap<PromptId, u8>>,
+        next_prompt_id: PromptId,
+    }
+
+    fn v1_snapshot(version: u32) -> Vec<u8> {
+        let (author, buyer, visitor) = (principal(1), principal(2), principal(3));
+        let v1_user = |id: UserId| V1User {
+            id,
+            username: None,
+            email: None,
+            joined_at: 10,
+            total_earnings: 0,
+            total_spent: 0,
+            prompts_created: 0,
+            prompts_purchased: 0,
+            balance: 0,
+        };
+        let state = V1State {
+            version,
+            prompts: HashMap::from([(
+                7,
+                V1Prompt {
+                    id: 7,
+                    title: "Model trainer".to_string(),
+                    description: "Fine-tuning checklists".to_string(),
+                    content: "Train the model".to_string(),
+                    author,
+                    category: PromptCategory::Education,
+                    tags: ["Machine Learning", "machine_learning", "GPT-4", "bad🙂"]
+                        .iter()
+                        .map(|tag| tag.to_string())
+                        .collect(),
+                    price: 0,
+                    is_premium: false,
+                    is_public: true,
+                    created_at: 100,
+                    updated_at: 200,
+                    likes: 1,
+                    purchases: 1,
+                    rating: 4.4, // drifted running average
+                    total_ratings: 9,
+                },
+            )]),
+            users: HashMap::from([(author, v1_user(author)), (buyer, v1_user(buyer))]),
+            purchases: Vec::new(),
+            user_purchases: HashMap::from([(buyer, vec![7])]),
+            user_likes: HashMap::from([(buyer, vec![7])]),
+            user_ratings: HashMap::from([
+                (buyer, HashMap::from([(7, 5)])),
+                (visitor, HashMap::from([(7, 2)])),
+            ]),
+            next_prompt_id: 8,
+        };
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&state, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn v1_snapshot_migrates_to_the_current_version() {
+        let (buyer, visitor) = (principal(2), principal(3));
+        let state: StableState = ciborium::from_reader(v1_snapshot(1).as_slice()).unwrap();
+        restore_state(state).unwrap();
+
+        PROMPTS.with(|p| {
+            let prompts = p.borrow();
+            let prompt = &prompts[&7];
+            // v2: tags canonicalized, duplicates and unusable tags dropped
+            assert_eq!(prompt.tags, vec!["machine-learning", "gpt-4"]);
+            // v3: revision history seeded
+            assert_eq!(prompt.revision, 1);
+            // v5: aggregates rebuilt from the ratings
+            assert_eq!(prompt.rating_counts, [0, 1, 0, 0, 1]);
+            assert_eq!((prompt.rating_sum, prompt.total_ratings), (7, 2));
+            assert_eq!(prompt.rating, 3.5);
+            assert_eq!((prompt.deleted_at, prompt.hidden_at), (None, None));
+        });
+        PROMPT_REVISIONS.with(|pr| {
+            let revisions = pr.borrow();
+            assert_eq!(revisions[&7].len(), 1);
+            assert_eq!(revisions[&7][0].revision, 1);
+            assert_eq!(revisions[&7][0].content, "Train the model");
+            assert_eq!(revisions[&7][0].created_at, 200);
+        });
+        // v4: reviews seeded from the ratings
+        REVIEWS.with(|r| {
+            let reviews = r.borrow();
+            assert_eq!(reviews[&7].len(), 2);
+            assert!(reviews[&7][&buyer].verified_purchase);
+            assert!(!reviews[&7][&visitor].verified_purchase);
+            assert_eq!(reviews[&7][&visitor].rating, 2);
+            assert_eq!(reviews[&7][&visitor].created_at, 100);
+        });
+        USERS.with(|u| {
+            let users = u.borrow();
+            assert_eq!(users[&buyer].status, UserStatus::Active);
+            assert_eq!(users[&buyer].warnings, 0);
+        });
+        assert_eq!(NEXT_PROMPT_ID.with(|id| *id.borrow()), 8);
+        assert_eq!(NEXT_REPORT_ID.with(|id| *id.borrow()), 1);
+        assert_eq!(NEXT_COMMENT_ID.with(|id| *id.borrow()), 1);
+        assert_eq!(
+            CONFIG.with(|c| c.borrow().rating_eligibility.clone()),
+            RatingEligibility::Anyone
+        );
+        assert!(SEARCH_INDEX.with(|si| si.borrow().tags.contains_key("machine-learning")));
+        assert_eq!(take_state().version, STATE_VERSION);
+    }
+
+    #[test]
+    fn snapshots_from_newer_versions_are_rejected() {
+        let state: StableState =
+            ciborium::from_reader(v1_snapshot(STATE_VERSION + 1).as_slice()).unwrap();
+        assert!(restore_state(state).is_err());
+        assert!(PROMPTS.with(|p| p.borrow().is_empty()));
+    }
+}
@@ -45,6 +45,7 @@ function App() {
   const [user, setUser] = useState(null);
   const [searchQuery, setSearchQuery] = useState("");
   const [selectedCategory, setSelectedCategory] = useState("");
+  const [sortOrder, setSortOrder] = useState("");
   const [isCreateModalOpen, setIsCreateModalOpen] = useState(false);
   const [loading, setLoading] = useState(true);
   const [isAuthenticated, setIsAuthenticated] = useState(false);
@@ -301,9 +302,12 @@ function App() {
         return;
       }
 
+      const [sortBy, direction] = sortOrder ? sortOrder.split(":") : [];
       const response = await icpAgent.searchPrompts(
         searchQuery,
-        selectedCategory || null
+        selectedCategory || null,
+        sortBy,
+        direction
       );
       if (response && response.success && response.data) {
         setPrompts(Array.isArray(response.data) ? response.data : []);
@@ -525,6 +529,8 @@ function App() {
               setSearchQuery={setSearchQuery}
               selectedCategory={selectedCategory}
               setSelectedCategory={setSelectedCategory}
+              sortOrder={sortOrder}
+              setSortOrder={setSortOrder}
               onSearch={handleSearch}
               PromptCategory={PromptCategory}
             />
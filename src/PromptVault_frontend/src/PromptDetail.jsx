@@ -37,7 +37,7 @@ const PromptDetail = ({ actor, user, promptId: propPromptId, onClose }) => {
 
   const fetchPromptDetails = async () => {
     try {
-      const response = await actor.get_prompt(parseInt(promptId));
+      const response = await actor.get_prompt_v1(parseInt(promptId));
       if (response.success) {
         setPrompt(response.data[0]);
         // Try to get content if user has access
@@ -54,7 +54,7 @@ const PromptDetail = ({ actor, user, promptId: propPromptId, onClose }) => {
 
   const fetchPromptContent = async () => {
     try {
-      const response = await actor.get_prompt_content(parseInt(promptId));
+      const response = await actor.get_prompt_content_v1(parseInt(promptId));
       if (response.success) {
         setPromptContent(response.data);
         setHasAccess(true);
@@ -70,7 +70,7 @@ const PromptDetail = ({ actor, user, promptId: propPromptId, onClose }) => {
 
     try {
       // Check if user has purchased this prompt
-      const purchasesResponse = await actor.get_user_purchases(user.id);
+      const purchasesResponse = await actor.get_user_purchases_v1(user.id);
       if (purchasesResponse.success) {
         const hasPurchased = purchasesResponse.data.includes(
           parseInt(promptId)
@@ -90,14 +90,17 @@ const PromptDetail = ({ actor, user, promptId: propPromptId, onClose }) => {
 
     setPurchasing(true);
     try {
-      const response = await actor.purchase_prompt(parseInt(promptId));
-      if (response.success) {
+      const response = await actor.purchase_prompt_v1(BigInt(promptId), []);
+      if ("Ok" in response) {
         alert("Purchase successful!");
         setHasAccess(true);
         await fetchPromptContent();
         await fetchPromptDetails(); // Refresh to update purchase count
       } else {
-        alert(response.error || "Purchase failed");
+        const [reason] = Object.keys(response.Err);
+        const detail =
+          reason === "Payment" ? Object.keys(response.Err.Payment)[0] : reason;
+        alert("Purchase failed: " + detail);
       }
     } catch (err) {
       alert("Purchase failed: " + err.message);
@@ -114,8 +117,8 @@ const PromptDetail = ({ actor, user, promptId: propPromptId, onClose }) => {
 
     try {
       const response = liked
-        ? await actor.unlike_prompt(parseInt(promptId))
-        : await actor.like_prompt(parseInt(promptId));
+        ? await actor.unlike_prompt_v1(parseInt(promptId))
+        : await actor.like_prompt_v1(parseInt(promptId));
 
       if (response.success) {
         setLiked(!liked);
@@ -136,9 +139,10 @@ const PromptDetail = ({ actor, user, promptId: propPromptId, onClose }) => {
 
     setSubmittingRating(true);
     try {
-      const response = await actor.rate_prompt({
+      const response = await actor.rate_prompt_v1({
         prompt_id: parseInt(promptId),
         rating: newRating,
+        review: [],
       });
 
       if (response.success) {
@@ -477,7 +481,7 @@ const PromptDetail = ({ actor, user, promptId: propPromptId, onClose }) => {
               )}
             </div>
 
-            {hasAccess || prompt.is_public ? (
+            {hasAccess ? (
               <div className="bg-gray-50 p-6 rounded-lg border">
                 <div className="mb-4">
                   <h4 className="font-medium text-gray-800 mb-2">
@@ -485,14 +489,12 @@ const PromptDetail = ({ actor, user, promptId: propPromptId, onClose }) => {
                   </h4>
                   <div className="relative">
                     <pre className="whitespace-pre-wrap text-sm text-gray-800 font-mono bg-white p-4 rounded border overflow-x-auto">
-                      {promptContent ||
-                        prompt.content ||
-                        "No content available"}
+                      {promptContent || "No content available"}
                     </pre>
                     <button
                       onClick={() => {
                         navigator.clipboard.writeText(
-                          promptContent || prompt.content || ""
+                          promptContent || ""
                         );
                         alert("Prompt copied to clipboard!");
                       }}
@@ -541,6 +543,12 @@ const PromptDetail = ({ actor, user, promptId: propPromptId, onClose }) => {
                   </p>
                 </div>
 
+                {prompt.preview && (
+                  <pre className="whitespace-pre-wrap text-left text-sm text-gray-500 font-mono bg-white p-4 rounded border mb-6">
+                    {prompt.preview}…
+                  </pre>
+                )}
+
                 <div className="bg-white p-4 rounded-lg mb-6">
                   <p className="text-sm text-gray-600 mb-2">What you'll get:</p>
                   <ul className="text-sm text-gray-700 space-y-1">
@@ -108,7 +108,7 @@ class ICPAgent {
 
   async createUser(username, email) {
     if (!this.isAuthenticated) throw new Error("Authentication required");
-    return this.actor.create_user(
+    return this.actor.create_user_v1(
       username ? [username] : [],
       email ? [email] : []
     );
@@ -117,11 +117,11 @@ class ICPAgent {
   async getCurrentUser() {
     if (!this.isAuthenticated)
       return { success: false, error: "Not authenticated" };
-    return this.actor.get_user(this.identity.getPrincipal());
+    return this.actor.get_user_v1(this.identity.getPrincipal());
   }
 
   async getUser(userId) {
-    return this.actor.get_user(userId);
+    return this.actor.get_user_v1(userId);
   }
 
   async createPrompt(data) {
@@ -136,81 +136,102 @@ class ICPAgent {
       price: BigInt(data.price),
       is_premium: data.is_premium,
       is_public: data.is_public,
+      variables: [],
+      messages: [],
     };
 
-    return this.actor.create_prompt(request);
+    return this.actor.create_prompt_v1(request);
   }
 
-  async getPublicPrompts() {
-    return this.actor.get_public_prompts();
+  // sortBy: one of the backend SortBy variants, e.g. "Newest"; direction: "Ascending" | "Descending"
+  sortOptions(sortBy, direction) {
+    if (!sortBy) return [];
+    return [
+      {
+        sort_by: { [sortBy]: null },
+        direction: direction ? [{ [direction]: null }] : [],
+      },
+    ];
   }
 
-  async searchPrompts(query, category) {
-    const categoryVariant = category ? { [category]: null } : [];
-    return this.actor.search_prompts(query, categoryVariant);
+  async getPublicPrompts(sortBy, direction) {
+    return this.actor.get_public_prompts_v1(this.sortOptions(sortBy, direction));
   }
 
-  async purchasePrompt(promptId) {
+  async searchPrompts(query, category, sortBy, direction) {
+    const categoryVariant = category ? [{ [category]: null }] : [];
+    return this.actor.search_prompts_v1(
+      query,
+      categoryVariant,
+      this.sortOptions(sortBy, direction)
+    );
+  }
+
+  async purchasePrompt(promptId, method) {
     if (!this.isAuthenticated) throw new Error("Authentication required");
-    return this.actor.purchase_prompt(BigInt(promptId));
+    // method: "Deposit" | "Approval"; omitted uses the deposit subaccount
+    return this.actor.purchase_prompt_v1(
+      BigInt(promptId),
+      method ? [{ [method]: null }] : []
+    );
   }
 
   async likePrompt(promptId) {
     if (!this.isAuthenticated) throw new Error("Authentication required");
-    return this.actor.like_prompt(BigInt(promptId));
+    return this.actor.like_prompt_v1(BigInt(promptId));
   }
 
   async getUserPurchases(userId) {
     const principal = userId || this.identity.getPrincipal();
-    return this.actor.get_user_purchases(principal);
+    return this.actor.get_user_purchases_v1(principal);
   }
 
   async getUserPrompts(userId) {
     const principal = userId || this.identity.getPrincipal();
-    return this.actor.get_user_prompts(principal);
+    return this.actor.get_user_prompts_v1(principal);
   }
 
   async getPromptContent(promptId) {
-    return this.actor.get_prompt_content(BigInt(promptId));
+    return this.actor.get_prompt_content_v1(BigInt(promptId));
   }
 
   async updateUsername(newUsername) {
     if (!this.isAuthenticated) throw new Error("Authentication required");
-    return this.actor.update_username(newUsername);
+    return this.actor.update_username_v1(newUsername);
   }
 
   async updatePrompt(request) {
     if (!this.isAuthenticated) throw new Error("Authentication required");
-    return this.actor.update_prompt(request);
+    return this.actor.update_prompt_v1(request);
   }
 
   async deletePrompt(promptId) {
     if (!this.isAuthenticated) throw new Error("Authentication required");
-    return this.actor.delete_prompt(BigInt(promptId));
+    return this.actor.delete_prompt_v1(BigInt(promptId));
   }
 
   async unlikePrompt(promptId) {
     if (!this.isAuthenticated) throw new Error("Authentication required");
-    return this.actor.unlike_prompt(BigInt(promptId));
+    return this.actor.unlike_prompt_v1(BigInt(promptId));
   }
 
   async ratePrompt(request) {
     if (!this.isAuthenticated) throw new Error("Authentication required");
-    return this.actor.rate_prompt(request);
+    return this.actor.rate_prompt_v1(request);
   }
 
   async getPrompt(promptId) {
-    return this.actor.get_prompt(BigInt(promptId));
+    return this.actor.get_prompt_v1(BigInt(promptId));
   }
 
   async getUserBalance(userId) {
     const principal = userId || this.identity.getPrincipal();
-    return this.actor.get_user_balance(principal);
+    return this.actor.get_user_balance_v1(principal);
   }
 
   async getUserLedgerBalance(userId) {
     const principal = userId || this.identity.getPrincipal();
-    return this.actor.get_user_ledger_balance(principal);
+    return this.actor.get_user_ledger_balance_v1(principal);
   }
 }
 
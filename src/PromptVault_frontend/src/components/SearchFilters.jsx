@@ -1,11 +1,26 @@
 import React from "react";
 import { Search } from "lucide-react";
 
+// Value is "<SortBy variant>:<SortDirection variant>"; empty keeps the server default
+const SORT_OPTIONS = [
+  { value: "", label: "Best match" },
+  { value: "Newest:Descending", label: "Newest" },
+  { value: "RecentlyUpdated:Descending", label: "Recently updated" },
+  { value: "Price:Ascending", label: "Price: low to high" },
+  { value: "Price:Descending", label: "Price: high to low" },
+  { value: "WeightedRating:Descending", label: "Top rated" },
+  { value: "Rating:Descending", label: "Highest average rating" },
+  { value: "MostPurchased:Descending", label: "Most purchased" },
+  { value: "MostLiked:Descending", label: "Most liked" },
+];
+
 const SearchFilters = ({
   searchQuery,
   setSearchQuery,
   selectedCategory,
   setSelectedCategory,
+  sortOrder,
+  setSortOrder,
   onSearch,
   PromptCategory,
 }) => {
@@ -39,6 +54,18 @@ const SearchFilters = ({
           ))}
         </select>
 
+        <select
+          value={sortOrder}
+          onChange={(e) => setSortOrder(e.target.value)}
+          className="px-4 py-2 border border-gray-300 rounded-md focus:ring-2 focus:ring-blue-500 focus:border-blue-500"
+        >
+          {SORT_OPTIONS.map((option) => (
+            <option key={option.value} value={option.value}>
+              {option.label}
+            </option>
+          ))}
+        </select>
+
         <button
           onClick={onSearch}
           className="px-6 py-2 bg-blue-600 text-white rounded-md hover:bg-blue-700"